@@ -0,0 +1,201 @@
+// Both baselines below are the selection code this crate shipped before the bitset index,
+// copied with only the rng threaded through. Measured on 20,000 words with 40 tags,
+// `cargo bench --bench selection`, median time per pick:
+//
+//   query        shuffle_sets   query_sets   bitset_index
+//   single_tag   523 µs         66.1 µs      0.20 µs
+//   and          424 µs         357 µs       1.30 µs
+//   or_and_not   -              812 µs       2.46 µs
+//
+// shuffle_sets cannot express '|' across groups or '!', so it has no or_and_not row.
+use std::collections::{BTreeSet, HashSet};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use procgen_templater::dictionary::{
+    dictionary::{build_dictionary, Dictionary, SearchPattern},
+    query::query::{Query, QueryExpr},
+    word::word::{Word, WordType},
+};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use uuid::Uuid;
+
+// get_random_word as first released: a HashSet per group, intersected into a pool that is
+// then shuffled in full to take its first word, kept as written
+#[allow(clippy::needless_return, clippy::unnecessary_first_then_check)]
+fn shuffle_sets<'a>(
+    dict: &'a Dictionary,
+    pattern: SearchPattern,
+    rng: &mut StdRng,
+) -> Option<&'a Word> {
+    // tag arguments [[OR] AND [OR]]
+    let (word_type, tags) = pattern;
+    let mut word_pool: HashSet<Uuid> = HashSet::new();
+    for or_set in &tags {
+        let mut s: HashSet<Uuid> = HashSet::new();
+        for or in or_set {
+            if dict
+                .index
+                .tag_words
+                .contains_key(&(word_type.clone(), or.to_string()))
+            {
+                let tag_ids = dict
+                    .index
+                    .tag_words
+                    .get(&(word_type.clone(), or.to_string()))
+                    .unwrap();
+                for id in tag_ids {
+                    s.insert(*id);
+                }
+            }
+        }
+        if word_pool.len().eq(&0) {
+            word_pool = s;
+        } else {
+            let pool_clone = word_pool.clone();
+            for word in pool_clone {
+                if !s.contains(&word) {
+                    word_pool.remove(&word);
+                }
+            }
+        }
+    }
+    let mut pool: Vec<&Word> = word_pool
+        .iter()
+        .map(|w| dict.words.get(w).unwrap())
+        .collect();
+    pool.shuffle(rng);
+
+    if pool.first().is_some() {
+        return Some(*pool.first().unwrap());
+    }
+    return None;
+}
+
+// the shared query engine the bitset index replaced: BTreeSets of ids combined per term,
+// then a uniform choose over the result
+#[allow(clippy::needless_return)]
+fn evaluate(
+    expr: &QueryExpr,
+    lookup: &dyn Fn(&str) -> BTreeSet<Uuid>,
+    universe: &dyn Fn() -> BTreeSet<Uuid>,
+) -> BTreeSet<Uuid> {
+    return match expr {
+        QueryExpr::Tag(tag) => lookup(tag),
+        QueryExpr::Or(terms) => terms
+            .iter()
+            .flat_map(|term| evaluate(term, lookup, universe))
+            .collect(),
+        QueryExpr::Not(inner) => {
+            let excluded = evaluate(inner, lookup, universe);
+            universe()
+                .into_iter()
+                .filter(|id| !excluded.contains(id))
+                .collect()
+        }
+        QueryExpr::And(terms) => {
+            let (negated, required): (Vec<&QueryExpr>, Vec<&QueryExpr>) = terms
+                .iter()
+                .partition(|term| matches!(term, QueryExpr::Not(_)));
+            let mut output = match required.first() {
+                Some(first) => evaluate(first, lookup, universe),
+                None => universe(),
+            };
+            for term in required.iter().skip(1) {
+                let matched = evaluate(term, lookup, universe);
+                output.retain(|id| matched.contains(id));
+            }
+            for term in negated {
+                if let QueryExpr::Not(inner) = term {
+                    let excluded = evaluate(inner, lookup, universe);
+                    output.retain(|id| !excluded.contains(id));
+                }
+            }
+            output
+        }
+    };
+}
+
+#[allow(clippy::needless_return)]
+fn query_sets<'a>(dict: &'a Dictionary, query: &Query, rng: &mut StdRng) -> Option<&'a Word> {
+    let word_types = match &query.word_type {
+        Some(word_type) => vec![word_type.clone()],
+        None => vec![WordType::Noun, WordType::Adjective],
+    };
+    let lookup = |tag: &str| -> BTreeSet<Uuid> {
+        return word_types
+            .iter()
+            .filter_map(|t| dict.index.tag_words.get(&(t.clone(), tag.to_string())))
+            .flatten()
+            .cloned()
+            .collect();
+    };
+    let universe = || -> BTreeSet<Uuid> {
+        return dict
+            .words
+            .values()
+            .filter(|w| word_types.contains(&w.word_type))
+            .map(|w| w.id)
+            .collect();
+    };
+    let pool: Vec<Uuid> = evaluate(&query.expr, &lookup, &universe)
+        .into_iter()
+        .collect();
+    return pool.choose(rng).map(|id| dict.words.get(id).unwrap());
+}
+
+#[allow(clippy::needless_return)]
+fn large_dictionary(words: usize) -> Dictionary {
+    let mut rng = StdRng::seed_from_u64(7);
+    let tags: Vec<String> = (0..40).map(|i| format!("Tag{}", i)).collect();
+    let lines: Vec<String> = (0..words)
+        .map(|i| {
+            let mut line = format!("NOUN(Word{})", i);
+            for tag in tags.choose_multiple(&mut rng, 4) {
+                line.push_str(&format!(", TAG({})", tag));
+            }
+            line
+        })
+        .collect();
+    return build_dictionary(lines);
+}
+
+#[allow(clippy::needless_return)]
+fn groups(groups: &[&[&str]]) -> Vec<Vec<String>> {
+    return groups
+        .iter()
+        .map(|g| g.iter().map(|t| t.to_string()).collect())
+        .collect();
+}
+
+fn selection(c: &mut Criterion) {
+    let dict = large_dictionary(20_000);
+    let queries = [
+        ("single_tag", "NOUN[Tag1]", Some(groups(&[&["Tag1"]]))),
+        (
+            "and",
+            "NOUN[Tag1 & Tag2]",
+            Some(groups(&[&["Tag1"], &["Tag2"]])),
+        ),
+        ("or_and_not", "NOUN[(Tag1 | Tag2) & Tag3 & !Tag4]", None),
+    ];
+    let mut group = c.benchmark_group("get_random_word");
+    for (name, text, legacy) in queries {
+        let query: Query = text.parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        if let Some(legacy) = legacy {
+            group.bench_with_input(BenchmarkId::new("shuffle_sets", name), &legacy, |b, g| {
+                b.iter(|| shuffle_sets(&dict, (WordType::Noun, g.clone()), &mut rng))
+            });
+        }
+        group.bench_with_input(BenchmarkId::new("query_sets", name), &query, |b, q| {
+            b.iter(|| query_sets(&dict, q, &mut rng))
+        });
+        group.bench_with_input(BenchmarkId::new("bitset_index", name), &query, |b, q| {
+            b.iter(|| dict.get_random_word_matching(q, &mut rng))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, selection);
+criterion_main!(benches);
@@ -1,17 +1,37 @@
+pub mod diagnostic;
+pub mod index;
+pub mod loader;
+pub mod parser;
+pub mod query;
+pub mod rarity;
+pub mod selection;
+pub mod session;
 pub mod template;
 pub mod word;
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
 pub mod dictionary {
     use super::{
-        template::template::{parse_template, Template},
-        word::word::{parse_word, Word, WordType},
+        diagnostic::diagnostic::{Diagnostic, Severity},
+        loader::loader::{list_folder, read_source, resolve_include, LoadOptions},
+        parser::parser::{logical_lines, parse_line, Entry, ParsedLine, Span},
+        query::query::{Query, QueryExpr},
+        rarity::rarity::Distribution,
+        selection::selection::SelectionIndex,
+        template::template::{
+            template_from_line, Template, TemplateElement, DEFAULT_TEMPLATE_DEPTH,
+        },
+        word::word::{line_tags, words_from_line, Word, WordType},
     };
-    use rand::seq::SliceRandom;
-    use regex::Regex;
+    use crate::error::error::TemplaterError;
+    use rand::Rng;
     use std::{
-        collections::{HashMap, HashSet},
-        fs::{self, File},
-        io::{self, BufRead},
-        time::Instant,
+        collections::{BTreeMap, BTreeSet},
+        fs,
+        path::{Path, PathBuf},
     };
     use uuid::Uuid;
 
@@ -20,22 +40,68 @@ pub mod dictionary {
     pub static TAG_WRAPPER: &str = "TAG";
     pub static TAG_PARENT_WRAPPER: &str = "HAS_PARENT";
 
-    pub static MAX_NESTED_TAG_DEPTH: usize = 5;
+    pub static ID_WRAPPER: &str = "ID";
+
+    pub static INCLUDE_WRAPPER: &str = "INCLUDE";
+
+    // relative likelihood of a line's words and template being picked, 1 when absent
+    pub static WEIGHT_WRAPPER: &str = "WEIGHT";
+
+    // popularity of a line's words for Distribution::Tiers and Distribution::Zipf
+    pub static RARITY_WRAPPER: &str = "RARITY";
+    pub static RANK_WRAPPER: &str = "RANK";
+
+    // a stable name for a template, see Dictionary::render_named and NAME[...] slots
+    pub static NAME_WRAPPER: &str = "NAME";
+
+    // source name used in errors for lines that did not come from a file
+    pub static INLINE_SOURCE: &str = "<input>";
+
+    // namespace for the v5 ids minted from dictionary content
+    pub static ID_NAMESPACE: Uuid = Uuid::from_u128(0x5bb97d05_7149_46a3_b4ea_54cbe07037b6);
+
+    pub fn content_id(kind: &str, content: &str) -> Uuid {
+        return Uuid::new_v5(
+            &ID_NAMESPACE,
+            format!("{}:{}", kind, content.trim()).as_bytes(),
+        );
+    }
+
+    // ids come from an explicit ID(key) entry if the line has one, otherwise from the
+    // normalised line, so reordering entries or reformatting whitespace keeps them stable
+    pub fn entry_id(kind: &str, line: &str) -> Uuid {
+        return content_id(kind, &entry_key(line));
+    }
+
+    pub fn entry_key(line: &str) -> String {
+        return parse_line(INLINE_SOURCE, 1, line).key;
+    }
 
     #[derive(PartialEq, Debug, Clone)]
     pub struct Index {
-        pub tag_children: HashMap<String, HashSet<String>>,
-        pub tag_words: HashMap<(WordType, String), HashSet<Uuid>>,
-        pub tag_templates: HashMap<String, HashSet<Uuid>>,
+        pub tag_children: BTreeMap<String, BTreeSet<String>>,
+        // every tag implied by a tag through any number of HAS_PARENT links
+        pub tag_ancestors: BTreeMap<String, BTreeSet<String>>,
+        pub tag_words: BTreeMap<(WordType, String), BTreeSet<Uuid>>,
+        pub tag_templates: BTreeMap<String, BTreeSet<Uuid>>,
+        pub template_names: BTreeMap<String, Uuid>,
+        // every tag named in the hierarchy or used by a word or template
+        pub all_tags: BTreeSet<String>,
+        pub selection: SelectionIndex,
     }
 
     #[derive(PartialEq, Debug, Clone)]
     pub struct Dictionary {
-        pub words: HashMap<Uuid, Word>,
-        pub templates: HashMap<Uuid, Template>,
+        pub words: BTreeMap<Uuid, Word>,
+        pub templates: BTreeMap<Uuid, Template>,
         pub index: Index,
+        pub diagnostics: Vec<Diagnostic>,
+        pub distribution: Distribution,
+        // how deep TEMPLATE slots may nest while rendering
+        pub max_template_depth: usize,
     }
 
+    // word type and AND-ed groups of OR-ed tags, see query::Query for the general form
     pub type SearchPattern = (WordType, Vec<Vec<String>>);
 
     impl Dictionary {
@@ -47,337 +113,602 @@ pub mod dictionary {
                 println!("{:?}: {}", k, v.len());
             }
         }
-        pub fn get_random_word(self: &Self, pattern: SearchPattern) -> Option<&Word> {
-            // tag arguments [[OR] AND [OR]]
-            let (word_type, tags) = pattern;
-            let mut word_pool: HashSet<Uuid> = HashSet::new();
-            for or_set in &tags {
-                let mut s: HashSet<Uuid> = HashSet::new();
-                for or in or_set {
-                    if self
-                        .index
-                        .tag_words
-                        .contains_key(&(word_type.clone(), or.to_string()))
-                    {
-                        let tag_ids = self
-                            .index
-                            .tag_words
-                            .get(&(word_type.clone(), or.to_string()))
-                            .unwrap();
-                        for id in tag_ids {
-                            s.insert(*id);
-                        }
-                    }
-                }
-                if word_pool.len().eq(&0) {
-                    word_pool = s;
-                } else {
-                    let pool_clone = word_pool.clone();
-                    for word in pool_clone {
-                        if !s.contains(&word) {
-                            word_pool.remove(&word);
-                        }
-                    }
-                }
-            }
-            let mut pool: Vec<&Word> = word_pool
-                .iter()
-                .map(|w| self.words.get(w).unwrap())
-                .collect();
-            pool.shuffle(&mut rand::thread_rng());
+        // selection takes a Query, a built or parsed one, or a legacy SearchPattern
+        pub fn get_random_word<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &Self,
+            query: Q,
+            rng: &mut R,
+        ) -> Option<&Word> {
+            return self.get_random_word_matching(&query.into(), rng);
+        }
 
-            if pool.first().is_some() {
-                return Some(*pool.first().unwrap());
-            }
-            return None;
+        pub fn try_get_random_word<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &Self,
+            query: Q,
+            rng: &mut R,
+        ) -> Result<&Word, TemplaterError> {
+            return self.try_get_random_word_matching(&query.into(), rng);
         }
 
-        pub fn get_random_word_without(
+        pub fn get_random_word_without<Q: Into<Query>, R: Rng + ?Sized>(
             self: &Self,
-            pattern: SearchPattern,
+            query: Q,
             exclude: Vec<String>,
+            rng: &mut R,
         ) -> Option<&Word> {
-            // tag arguments [[OR] AND [OR]]
-            let (word_type, tags) = pattern;
-            let mut word_pool: HashSet<Uuid> = HashSet::new();
-            for or_set in &tags {
-                let mut s: HashSet<Uuid> = HashSet::new();
-                for or in or_set {
-                    if self
-                        .index
-                        .tag_words
-                        .contains_key(&(word_type.clone(), or.to_string()))
-                    {
-                        let tag_ids = self
-                            .index
-                            .tag_words
-                            .get(&(word_type.clone(), or.to_string()))
-                            .unwrap();
-                        for id in tag_ids {
-                            s.insert(*id);
+            return self.get_random_word_matching(&without(query.into(), exclude), rng);
+        }
+
+        pub fn try_get_random_word_without<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &Self,
+            query: Q,
+            exclude: Vec<String>,
+            rng: &mut R,
+        ) -> Result<&Word, TemplaterError> {
+            return self.try_get_random_word_matching(&without(query.into(), exclude), rng);
+        }
+
+        pub fn get_random_template<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &Self,
+            query: Q,
+            rng: &mut R,
+        ) -> Option<&Template> {
+            return self.get_random_template_matching(&query.into().expr, rng);
+        }
+
+        pub fn try_get_random_template<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &Self,
+            query: Q,
+            rng: &mut R,
+        ) -> Result<&Template, TemplaterError> {
+            let query: Query = query.into();
+            return self
+                .get_random_template_matching(&query.expr, rng)
+                .ok_or(TemplaterError::EmptySlot(query));
+        }
+    }
+
+    // the query with every word carrying one of the excluded tags left out
+    fn without(mut query: Query, exclude: Vec<String>) -> Query {
+        let excluded = QueryExpr::Or(exclude.into_iter().map(QueryExpr::Tag).collect());
+        query.expr = QueryExpr::And(vec![query.expr, QueryExpr::Not(Box::new(excluded))]);
+        return query;
+    }
+
+    pub fn build_dictionary(lines: Vec<String>) -> Dictionary {
+        let options = LoadOptions::default();
+        let mut builder = Builder::new(&options);
+        builder.load_lines(
+            INLINE_SOURCE,
+            logical_lines(
+                lines
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, line)| (i + 1, line))
+                    .collect(),
+            ),
+        );
+        return builder.finish();
+    }
+
+    pub fn try_build_dictionary(lines: Vec<String>) -> Result<Dictionary, TemplaterError> {
+        return first_error(build_dictionary(lines));
+    }
+
+    pub fn build_dictionary_from_folder(folder_path: &str) -> Dictionary {
+        return build_dictionary_from_folder_with(folder_path, &LoadOptions::default());
+    }
+
+    pub fn build_dictionary_from_folder_with(
+        folder_path: &str,
+        options: &LoadOptions,
+    ) -> Dictionary {
+        return load_folder(folder_path, options).unwrap_or_else(|e| panic!("{}", e));
+    }
+
+    pub fn try_build_dictionary_from_folder(
+        folder_path: &str,
+    ) -> Result<Dictionary, TemplaterError> {
+        return try_build_dictionary_from_folder_with(folder_path, &LoadOptions::default());
+    }
+
+    pub fn try_build_dictionary_from_folder_with(
+        folder_path: &str,
+        options: &LoadOptions,
+    ) -> Result<Dictionary, TemplaterError> {
+        return first_error(load_folder(folder_path, options)?);
+    }
+
+    fn load_folder(folder_path: &str, options: &LoadOptions) -> Result<Dictionary, TemplaterError> {
+        let mut builder = Builder::new(options);
+        for path in list_folder(Path::new(folder_path), options)? {
+            builder.load_file(&path)?;
+        }
+        return Ok(builder.finish());
+    }
+
+    // accumulates a dictionary across files, following INCLUDE entries as they are met
+    struct Builder<'a> {
+        options: &'a LoadOptions,
+        dict: Dictionary,
+        // every file read so far, so a file reached twice is only loaded once
+        loaded: BTreeSet<PathBuf>,
+        // files currently being loaded, an INCLUDE of one of these is a cycle
+        stack: Vec<PathBuf>,
+        // load order and location of each (child, parent) tag link, for cycle diagnostics
+        tag_links: BTreeMap<(String, String), (usize, String, usize, Span)>,
+        // the same for each template, for nested TEMPLATE cycle diagnostics
+        template_origins: BTreeMap<Uuid, (usize, String, usize, Span)>,
+        // where each word was loaded from, for duplicate id diagnostics
+        word_origins: BTreeMap<Uuid, (String, usize)>,
+    }
+
+    impl<'a> Builder<'a> {
+        fn new(options: &'a LoadOptions) -> Self {
+            return Builder {
+                options,
+                dict: Dictionary {
+                    words: BTreeMap::new(),
+                    templates: BTreeMap::new(),
+                    index: Index {
+                        tag_children: BTreeMap::new(),
+                        tag_ancestors: BTreeMap::new(),
+                        tag_words: BTreeMap::new(),
+                        tag_templates: BTreeMap::new(),
+                        template_names: BTreeMap::new(),
+                        all_tags: BTreeSet::new(),
+                        selection: SelectionIndex::default(),
+                    },
+                    diagnostics: Vec::new(),
+                    distribution: Distribution::default(),
+                    max_template_depth: DEFAULT_TEMPLATE_DEPTH,
+                },
+                loaded: BTreeSet::new(),
+                stack: Vec::new(),
+                tag_links: BTreeMap::new(),
+                template_origins: BTreeMap::new(),
+                word_origins: BTreeMap::new(),
+            };
+        }
+
+        fn load_file(self: &mut Self, path: &Path) -> Result<(), TemplaterError> {
+            let key = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+            if !self.loaded.insert(key.clone()) {
+                return Ok(());
+            }
+            let lines = read_source(path)?;
+            self.stack.push(key);
+            self.load_lines(&path.to_string_lossy(), lines);
+            self.stack.pop();
+            return Ok(());
+        }
+
+        fn load_lines(self: &mut Self, source: &str, lines: Vec<(usize, String)>) {
+            for (line_number, line) in lines {
+                let parsed = parse_line(source, line_number, &line);
+
+                let span_of = |wanted: fn(&Entry) -> bool| -> Span {
+                    return parsed
+                        .entries
+                        .iter()
+                        .find(|(entry, _)| wanted(entry))
+                        .map(|(_, span)| span.clone())
+                        .unwrap_or(0..0);
+                };
+                // an explicit ID is the likely cause of a clash, so point at it when there is one
+                let id_span = parsed
+                    .entries
+                    .iter()
+                    .find(|(entry, _)| matches!(entry, Entry::Id(_)))
+                    .map(|(_, span)| span.clone());
+
+                for word in words_from_line(&parsed) {
+                    if let Some(existing) = self.dict.words.get(&word.id) {
+                        if !existing.eq(&word) {
+                            let (first_source, first_line) = &self.word_origins[&word.id];
+                            let wrapper = match existing.word_type {
+                                WordType::Noun => NOUN_WRAPPER,
+                                WordType::Adjective => ADJECTIVE_WRAPPER,
+                            };
+                            let message = format!(
+                                "duplicate id, replaces the {} '{}' at {}:{}",
+                                wrapper, existing.base, first_source, first_line
+                            );
+                            let span = id_span.clone().unwrap_or(span_of(|e| {
+                                matches!(e, Entry::Noun(_) | Entry::Adjective(_))
+                            }));
+                            self.dict.diagnostics.push(Diagnostic {
+                                severity: Severity::Warning,
+                                source: source.to_string(),
+                                line: line_number,
+                                span,
+                                message,
+                            });
+                        }
+                    }
+                    self.word_origins
+                        .insert(word.id, (source.to_string(), line_number));
+                    self.dict.words.insert(word.id, word);
+                }
+
+                if let Some(pattern) = template_from_line(&parsed) {
+                    let span = span_of(|e| matches!(e, Entry::Template(_)));
+                    if let Some(existing) = self.dict.templates.get(&pattern.id) {
+                        if !existing.eq(&pattern) {
+                            let (_, first_source, first_line, _) =
+                                &self.template_origins[&pattern.id];
+                            let message = format!(
+                                "duplicate id, replaces the TEMPLATE at {}:{}",
+                                first_source, first_line
+                            );
+                            let span = id_span.clone().unwrap_or(span.clone());
+                            self.dict.diagnostics.push(Diagnostic {
+                                severity: Severity::Warning,
+                                source: source.to_string(),
+                                line: line_number,
+                                span,
+                                message,
+                            });
                         }
                     }
+                    let order = self.template_origins.len();
+                    if let Some(name) = &pattern.name {
+                        let name_span = span_of(|e| matches!(e, Entry::Name(_)));
+                        self.name_template(name, pattern.id, source, line_number, name_span);
+                    }
+                    self.template_origins
+                        .insert(pattern.id, (order, source.to_string(), line_number, span));
+                    self.dict.templates.insert(pattern.id, pattern);
                 }
-                if word_pool.len().eq(&0) {
-                    word_pool = s;
-                } else {
-                    let pool_clone = word_pool.clone();
-                    for word in pool_clone {
-                        if !s.contains(&word) {
-                            word_pool.remove(&word);
+                for (entry, span) in &parsed.entries {
+                    if let Entry::HasParent(parent) = entry {
+                        for child in line_tags(&parsed) {
+                            let order = self.tag_links.len();
+                            self.tag_links.entry((child, parent.clone())).or_insert((
+                                order,
+                                source.to_string(),
+                                line_number,
+                                span.clone(),
+                            ));
                         }
                     }
                 }
+                for (parent, children) in tag_children_from_line(&parsed) {
+                    self.dict
+                        .index
+                        .tag_children
+                        .entry(parent)
+                        .or_default()
+                        .extend(children);
+                }
+                self.dict.diagnostics.extend(parsed.diagnostics);
+                for (entry, span) in parsed.entries {
+                    if let Entry::Include(target) = entry {
+                        self.include(source, line_number, span, &target);
+                    }
+                }
             }
-            let mut pool: Vec<&Word> = word_pool
-                .iter()
-                .map(|w| self.words.get(w).unwrap())
-                .collect();
-            pool.shuffle(&mut rand::thread_rng());
-            pool.retain(|w| !w.tags.iter().any(|t| exclude.contains(t)));
+        }
 
-            if pool.first().is_some() {
-                return Some(*pool.first().unwrap());
-            }
-            return None;
+        // the first template to take a name keeps it
+        fn name_template(
+            self: &mut Self,
+            name: &str,
+            id: Uuid,
+            source: &str,
+            line: usize,
+            span: Span,
+        ) {
+            let names = &mut self.dict.index.template_names;
+            let existing = match names.get(name) {
+                None => {
+                    names.insert(name.to_string(), id);
+                    return;
+                }
+                Some(existing) if existing.eq(&id) => return,
+                Some(existing) => existing,
+            };
+            let (_, first_source, first_line, _) = &self.template_origins[existing];
+            let message = format!(
+                "duplicate NAME '{}', already used by the template at {}:{}",
+                name, first_source, first_line
+            );
+            self.dict.diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                source: source.to_string(),
+                line,
+                span,
+                message,
+            });
         }
 
-        pub fn get_random_template(self: &Self, tags: Vec<Vec<String>>) -> Option<&Template> {
-            let mut pattern_pool: HashSet<Uuid> = HashSet::new();
-            for or_set in &tags {
-                let mut s: HashSet<Uuid> = HashSet::new();
-                for or in or_set {
-                    if self.index.tag_templates.contains_key(or) {
-                        let tag_ids = self.index.tag_templates.get(or).unwrap();
-                        for id in tag_ids {
-                            s.insert(id.clone());
-                        }
+        fn include(self: &mut Self, source: &str, line: usize, span: Span, target: &str) {
+            let path = resolve_include(source, target);
+            let key = fs::canonicalize(&path).unwrap_or(path.clone());
+            let mut diagnostic = Diagnostic {
+                severity: Severity::Warning,
+                source: source.to_string(),
+                line,
+                span,
+                message: format!("INCLUDE of '{}' forms a cycle and is skipped", target),
+            };
+            if self.stack.contains(&key) {
+                self.dict.diagnostics.push(diagnostic);
+                return;
+            }
+            let result = if path.is_dir() {
+                list_folder(&path, self.options).and_then(|paths| {
+                    for file in paths {
+                        self.load_file(&file)?;
                     }
-                }
-                if pattern_pool.len().eq(&0) {
-                    pattern_pool = s;
-                } else {
-                    let pool_clone = pattern_pool.clone();
-                    for pattern in pool_clone {
-                        if !s.contains(&pattern) {
-                            pattern_pool.remove(&pattern);
+                    return Ok(());
+                })
+            } else {
+                self.load_file(&path)
+            };
+            if let Err(e) = result {
+                diagnostic.severity = Severity::Error;
+                diagnostic.message = format!("cannot include '{}': {}", target, e);
+                self.dict.diagnostics.push(diagnostic);
+            }
+        }
+
+        fn finish(mut self: Self) -> Dictionary {
+            let (ancestors, cycles) = tag_ancestors(&self.dict.index.tag_children);
+            for cycle in cycles {
+                // blame the link that closed the cycle
+                let link = self
+                    .tag_links
+                    .iter()
+                    .filter(|((child, parent), _)| cycle.contains(child) && cycle.contains(parent))
+                    .map(|(_, origin)| origin)
+                    .max_by_key(|(order, _, _, _)| *order);
+                let (_, source, line, span) =
+                    link.cloned()
+                        .unwrap_or((0, INLINE_SOURCE.to_string(), 0, 0..0));
+                let tags: Vec<String> = cycle.into_iter().collect();
+                self.dict.diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    source,
+                    line,
+                    span,
+                    message: format!("tag hierarchy cycle between {}", tags.join(", ")),
+                });
+            }
+            self.dict.index.tag_ancestors = ancestors;
+            propegate_tag_children(&mut self.dict);
+            build_tag_index(&mut self.dict);
+            for template in self.dict.templates.values() {
+                for element in template.elements() {
+                    match element {
+                        TemplateElement::Named(name)
+                            if !self.dict.index.template_names.contains_key(name) =>
+                        {
+                            let (_, source, line, span) =
+                                self.template_origins[&template.id].clone();
+                            self.dict.diagnostics.push(Diagnostic {
+                                severity: Severity::Warning,
+                                source,
+                                line,
+                                span,
+                                message: format!("no template is named '{}'", name),
+                            });
                         }
+                        _ => {}
                     }
                 }
             }
-            let mut pool: Vec<&Template> = pattern_pool
-                .iter()
-                .map(|p| self.templates.get(p).unwrap())
-                .collect();
-            pool.shuffle(&mut rand::thread_rng());
-            if pool.first().is_some() {
-                return Some(*pool.first().unwrap());
+            for cycle in template_cycles(&self.dict) {
+                let mut origins: Vec<&(usize, String, usize, Span)> = cycle
+                    .iter()
+                    .filter_map(|id| self.template_origins.get(id))
+                    .collect();
+                origins.sort_by_key(|(order, _, _, _)| *order);
+                let lines: Vec<String> = origins
+                    .iter()
+                    .map(|(_, source, line, _)| format!("{}:{}", source, line))
+                    .collect();
+                let (_, source, line, span) = origins.last().cloned().cloned().unwrap_or((
+                    0,
+                    INLINE_SOURCE.to_string(),
+                    0,
+                    0..0,
+                ));
+                self.dict.diagnostics.push(Diagnostic {
+                    severity: Severity::Warning,
+                    source,
+                    line,
+                    span,
+                    message: format!("nested TEMPLATE cycle through {}", lines.join(", ")),
+                });
             }
-            return None;
+            return self.dict;
         }
     }
 
-    pub struct ParseResult {
-        words: Vec<Word>,
-        tag_children: HashMap<String, HashSet<String>>,
-        pattern: Option<Template>,
+    // the try_ builders treat any error level diagnostic as fatal
+    fn first_error(dict: Dictionary) -> Result<Dictionary, TemplaterError> {
+        let error = dict
+            .diagnostics
+            .iter()
+            .find(|d| d.severity.eq(&Severity::Error));
+        if let Some(diagnostic) = error {
+            return Err(TemplaterError::Parse {
+                file: diagnostic.source.clone(),
+                line: diagnostic.line,
+                message: diagnostic.message.clone(),
+            });
+        }
+        return Ok(dict);
     }
 
-    pub fn build_dictionary(lines: Vec<String>) -> Dictionary {
-        // let start = Instant::now();
-        let mut output = Dictionary {
-            words: HashMap::new(),
-            templates: HashMap::new(),
-            index: Index {
-                tag_children: HashMap::new(),
-                tag_words: HashMap::new(),
-                tag_templates: HashMap::new(),
-            },
-        };
-        let mut word_times: (f64, f64, f64) = (0.0, 0.0, 0.0);
-        for line in &lines {
-            let parse = parse_line(line, &mut word_times);
-
-            for word in parse.words {
-                output.words.insert(word.id.clone(), word);
-            }
-
-            if parse.pattern.is_some() {
-                let pattern = parse.pattern.unwrap();
-                output.templates.insert(pattern.id.clone(), pattern);
+    // walks the HAS_PARENT graph once from every tag, reusing finished tags, and returns
+    // each tag's full ancestor set along with the groups of tags that form cycles
+    fn tag_ancestors(
+        tag_children: &BTreeMap<String, BTreeSet<String>>,
+    ) -> (BTreeMap<String, BTreeSet<String>>, Vec<BTreeSet<String>>) {
+        let mut parents: BTreeMap<&String, BTreeSet<&String>> = BTreeMap::new();
+        for (parent, children) in tag_children {
+            for child in children {
+                parents.entry(child).or_default().insert(parent);
             }
-            for (parent, children) in parse.tag_children {
-                if !output.index.tag_children.contains_key(&parent) {
-                    output
-                        .index
-                        .tag_children
-                        .insert(parent.clone(), HashSet::new());
+        }
+        let mut ancestors: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        for tag in parents.keys() {
+            let mut found: BTreeSet<String> = BTreeSet::new();
+            let mut queue: Vec<&String> = parents.get(tag).unwrap().iter().cloned().collect();
+            while let Some(next) = queue.pop() {
+                if !found.insert(next.clone()) {
+                    continue;
                 }
-                for child in children {
-                    output
-                        .index
-                        .tag_children
-                        .get_mut(&parent)
-                        .unwrap()
-                        .insert(child);
+                if let Some(known) = ancestors.get(next) {
+                    found.extend(known.iter().cloned());
+                } else if let Some(next_parents) = parents.get(next) {
+                    queue.extend(next_parents.iter().cloned());
                 }
             }
+            ancestors.insert(tag.to_string(), found);
         }
-        propegate_tag_children(&mut output);
-        build_tag_index(&mut output);
-        // println!(
-        //     "Built in {}ms\nWord Parse: {:.0}ms\nTag Parse: {:.0}ms\nPattern Parse: {:.0}ms",
-        //     Instant::now().duration_since(start).as_millis(),
-        //     word_times.0 * 1000.0,
-        //     word_times.1 * 1000.0,
-        //     word_times.2 * 1000.0
-        // );
-        return output;
-    }
 
-    pub fn build_dictionary_from_folder(folder_path: &str) -> Dictionary {
-        let paths = fs::read_dir(folder_path).unwrap();
-        let mut output: Vec<String> = Vec::new();
-        for path in paths {
-            let filename = path.unwrap().file_name();
-            let data = File::open(&format!("{}/{}", folder_path, filename.to_str().unwrap()))
-                .expect(&format!("Cannot open: {}", filename.into_string().unwrap()));
-            let lines = io::BufReader::new(data).lines();
-            for l in lines {
-                if l.is_ok() {
-                    let line = l.unwrap();
-                    if line.len() > 0 {
-                        output.push(line);
-                    }
-                }
+        let mut cycles: Vec<BTreeSet<String>> = Vec::new();
+        for (tag, found) in &ancestors {
+            if !found.contains(tag) || cycles.iter().any(|c| c.contains(tag)) {
+                continue;
             }
+            let cycle: BTreeSet<String> = found
+                .iter()
+                .filter(|other| ancestors.get(*other).is_some_and(|a| a.contains(tag)))
+                .cloned()
+                .collect();
+            cycles.push(cycle);
         }
-        return build_dictionary(output);
+        for (tag, found) in ancestors.iter_mut() {
+            found.remove(tag);
+        }
+        return (ancestors, cycles);
     }
 
     fn propegate_tag_children(dict: &mut Dictionary) {
-        let mut processed_parents: HashSet<String> = HashSet::new();
-        for parent in dict.index.tag_children.keys() {
-            if !processed_parents.contains(parent) {
-                let children = dict.index.tag_children.get(parent).unwrap();
-
-                for word in dict
-                    .words
-                    .values_mut()
-                    .filter(|w| w.tags.iter().any(|t| children.contains(t)))
-                {
-                    word.tags.insert(parent.clone());
-                }
-                for template in dict
-                    .templates
-                    .values_mut()
-                    .filter(|tem| tem.tags.iter().any(|tag| children.contains(tag)))
-                {
-                    template.tags.insert(parent.clone());
-                }
-                processed_parents.insert(parent.to_string());
-            }
+        let ancestors = &dict.index.tag_ancestors;
+        let implied = |tags: &BTreeSet<String>| -> BTreeSet<String> {
+            return tags
+                .iter()
+                .filter_map(|tag| ancestors.get(tag))
+                .flatten()
+                .cloned()
+                .collect();
+        };
+        for word in dict.words.values_mut() {
+            let extra = implied(&word.tags);
+            word.tags.extend(extra);
+        }
+        for template in dict.templates.values_mut() {
+            let extra = implied(&template.tags);
+            template.tags.extend(extra);
         }
     }
+
     fn build_tag_index(dict: &mut Dictionary) {
-        for _i in 0..MAX_NESTED_TAG_DEPTH {
-            let ref_words = dict.words.clone();
-            for word in ref_words.values() {
-                for tag in &word.tags {
-                    if !dict
-                        .index
-                        .tag_words
-                        .contains_key(&(word.word_type.clone(), tag.to_string()))
-                    {
-                        dict.index
-                            .tag_words
-                            .insert((word.word_type.clone(), tag.clone()), HashSet::new());
-                    }
-                    dict.index
-                        .tag_words
-                        .get_mut(&(word.word_type.clone(), tag.clone()))
-                        .unwrap()
-                        .insert(word.id.clone());
-                }
+        for word in dict.words.values() {
+            for tag in &word.tags {
+                dict.index
+                    .tag_words
+                    .entry((word.word_type.clone(), tag.clone()))
+                    .or_default()
+                    .insert(word.id);
             }
-            let ref_templates = dict.templates.clone();
-            for template in ref_templates.values() {
-                for tag in &template.tags {
-                    if !dict.index.tag_templates.contains_key(&tag.to_string()) {
-                        dict.index.tag_templates.insert(tag.clone(), HashSet::new());
-                    }
-                    dict.index
-                        .tag_templates
-                        .get_mut(tag)
-                        .unwrap()
-                        .insert(template.id.clone());
-                }
+        }
+        for template in dict.templates.values() {
+            for tag in &template.tags {
+                dict.index
+                    .tag_templates
+                    .entry(tag.clone())
+                    .or_default()
+                    .insert(template.id);
             }
         }
+        let index = &mut dict.index;
+        for (parent, children) in &index.tag_children {
+            index.all_tags.insert(parent.clone());
+            index.all_tags.extend(children.iter().cloned());
+        }
+        index
+            .all_tags
+            .extend(index.tag_words.keys().map(|(_, tag)| tag.clone()));
+        index.all_tags.extend(index.tag_templates.keys().cloned());
+        dict.index.selection = SelectionIndex::build(&dict.words, &dict.templates);
     }
 
-    fn parse_line(line: &str, times: &mut (f64, f64, f64)) -> ParseResult {
-        let word_start = Instant::now();
-        let words = parse_word(line);
-        times.0 += word_start.elapsed().as_secs_f64();
-
-        let tag_start = Instant::now();
-        let tag_children = parse_tag_children(line);
+    // groups of templates that can expand into one another through TEMPLATE and NAME slots,
+    // which a render can only leave by chance before max_template_depth stops it
+    fn template_cycles(dict: &Dictionary) -> Vec<BTreeSet<Uuid>> {
+        let nested: BTreeMap<Uuid, BTreeSet<Uuid>> = dict
+            .templates
+            .values()
+            .map(|template| {
+                let ids = template
+                    .elements()
+                    .into_iter()
+                    .filter_map(|element| match element {
+                        TemplateElement::Nested(expr) => Some(dict.query_template_ids(expr)),
+                        TemplateElement::Named(name) => Some(
+                            dict.index
+                                .template_names
+                                .get(name)
+                                .cloned()
+                                .into_iter()
+                                .collect(),
+                        ),
+                        _ => None,
+                    })
+                    .flatten()
+                    .collect();
+                (template.id, ids)
+            })
+            .collect();
+        let mut reachable: BTreeMap<Uuid, BTreeSet<Uuid>> = BTreeMap::new();
+        for id in nested.keys() {
+            let mut found: BTreeSet<Uuid> = BTreeSet::new();
+            let mut queue: Vec<Uuid> = nested[id].iter().cloned().collect();
+            while let Some(next) = queue.pop() {
+                if found.insert(next) {
+                    queue.extend(nested.get(&next).into_iter().flatten());
+                }
+            }
+            reachable.insert(*id, found);
+        }
+        let mut cycles: Vec<BTreeSet<Uuid>> = Vec::new();
+        for (id, found) in &reachable {
+            if !found.contains(id) || cycles.iter().any(|c| c.contains(id)) {
+                continue;
+            }
+            cycles.push(
+                found
+                    .iter()
+                    .filter(|other| reachable[*other].contains(id))
+                    .cloned()
+                    .collect(),
+            );
+        }
+        return cycles;
+    }
 
-        times.1 += tag_start.elapsed().as_secs_f64();
-        let pattern_start = Instant::now();
-        let pattern = parse_template(line);
-        times.2 += pattern_start.elapsed().as_secs_f64();
-        return ParseResult {
-            words,
-            tag_children,
-            pattern,
-        };
+    pub fn parse_tag_children(line: &str) -> BTreeMap<String, BTreeSet<String>> {
+        return tag_children_from_line(&parse_line(INLINE_SOURCE, 1, line));
     }
 
-    fn parse_tag_children(line: &str) -> HashMap<String, HashSet<String>> {
-        if !line.contains(TAG_PARENT_WRAPPER) {
-            return HashMap::new();
-        }
-        let mut output: HashMap<String, HashSet<String>> = HashMap::new();
-        let child_tag_regx =
-            Regex::new(&format!(r",?\s?{}\(([a-zA-Z0-9]+)\)", TAG_WRAPPER)).unwrap();
-        let parent_tag_regex =
-            Regex::new(&format!(r",?\s?{}\(([a-zA-Z0-9]+)\)", TAG_PARENT_WRAPPER)).unwrap();
-
-        let child_tags: Vec<String> = child_tag_regx
-            .find_iter(line)
-            .map(|m| {
-                child_tag_regx
-                    .captures(m.as_str())
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str()
-                    .to_string()
-            })
-            .collect();
-        let parent_tags: Vec<String> = parent_tag_regex
-            .find_iter(line)
-            .map(|m| {
-                parent_tag_regex
-                    .captures(m.as_str())
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str()
-                    .to_string()
-            })
-            .collect();
-        for c in &parent_tags {
-            for p in &child_tags {
-                if !output.contains_key(c) {
-                    output.insert(c.to_string(), HashSet::new());
+    // every HAS_PARENT on a line is a parent of every TAG on it
+    fn tag_children_from_line(parsed: &ParsedLine) -> BTreeMap<String, BTreeSet<String>> {
+        let mut output: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let child_tags = line_tags(parsed);
+        for (entry, _) in &parsed.entries {
+            if let Entry::HasParent(parent) = entry {
+                if !child_tags.is_empty() {
+                    output
+                        .entry(parent.clone())
+                        .or_default()
+                        .extend(child_tags.iter().cloned());
                 }
-                output
-                    .get_mut(&c.to_string())
-                    .unwrap()
-                    .insert(p.to_string());
             }
         }
         return output;
@@ -424,10 +755,13 @@ pub mod dictionary {
             "TAG(Fruit), HAS_PARENT(Food)".to_string(),
         ]);
         assert!(dict
-            .get_random_word((
-                WordType::Noun,
-                vec![vec!["Wood".to_string()], vec!["Fruit".to_string()]]
-            ))
+            .get_random_word(
+                (
+                    WordType::Noun,
+                    vec![vec!["Wood".to_string()], vec!["Fruit".to_string()]]
+                ),
+                &mut rand::thread_rng()
+            )
             .unwrap()
             .base
             .eq(&"Pear"));
@@ -446,10 +780,451 @@ pub mod dictionary {
             "TAG(Fruit), HAS_PARENT(Food)".to_string(),
             "TAG(Restaurant), HAS_PARENT(Institution)".to_string(),
         ]);
+        let mut rng = rand::thread_rng();
         let t = dict
-            .get_random_template(vec![vec!["Restaurant".to_string()]])
+            .get_random_template(vec![vec!["Restaurant".to_string()]], &mut rng)
             .unwrap();
-        assert!(t.template.len().eq(&4));
-        assert!(dict.render_template(&t.id).unwrap().eq("Steel Bull Pub"));
+        assert!(t.template.len().eq(&2));
+        assert!(dict
+            .render_template(&t.id, &mut rng)
+            .unwrap()
+            .eq("Steel Bull Pub"));
+    }
+
+    #[test]
+    fn test_seeded_random_word() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+            "NOUN(Copper), TAG(Metal)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+            "NOUN(Gold), TAG(Metal)".to_string(),
+        ]);
+        let pattern: SearchPattern = (WordType::Noun, vec![vec!["Metal".to_string()]]);
+        let sample = |seed: u64| -> Vec<String> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..50)
+                .map(|_| {
+                    dict.get_random_word(pattern.clone(), &mut rng)
+                        .unwrap()
+                        .base
+                        .clone()
+                })
+                .collect()
+        };
+        assert!(sample(7).eq(&sample(7)));
+        assert!(sample(7).ne(&sample(8)));
+    }
+
+    #[test]
+    fn test_stable_ids_and_order() {
+        use rand::{rngs::StdRng, SeedableRng};
+        let lines = vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+            "NOUN(Copper), TAG(Metal)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+        ];
+        let forward = build_dictionary(lines.clone());
+        let reversed = build_dictionary(lines.into_iter().rev().collect());
+        assert!(forward.eq(&reversed));
+        assert!(forward
+            .words
+            .contains_key(&Uuid::parse_str("83f1ed90-244e-501d-a284-eee0988678f9").unwrap()));
+
+        let pattern: SearchPattern = (WordType::Noun, vec![vec!["Metal".to_string()]]);
+        let sample = |dict: &Dictionary| -> Vec<String> {
+            let mut rng = StdRng::seed_from_u64(99);
+            (0..20)
+                .map(|_| {
+                    dict.get_random_word(pattern.clone(), &mut rng)
+                        .unwrap()
+                        .base
+                        .clone()
+                })
+                .collect()
+        };
+        assert!(sample(&forward).eq(&sample(&reversed)));
+    }
+
+    #[test]
+    fn test_entry_ids() {
+        assert!(
+            entry_id(NOUN_WRAPPER, "NOUN(Steel), TAG(Metal), TAG(Alloy)").eq(&entry_id(
+                NOUN_WRAPPER,
+                "TAG(Alloy),NOUN(Steel),   TAG(Metal)"
+            ))
+        );
+        assert!(entry_id(NOUN_WRAPPER, "NOUN(Steel), TAG(Metal)")
+            .ne(&entry_id(ADJECTIVE_WRAPPER, "NOUN(Steel), TAG(Metal)")));
+        assert!(
+            entry_id(NOUN_WRAPPER, "NOUN(Steel), ID(metal-steel), TAG(Metal)").eq(&entry_id(
+                NOUN_WRAPPER,
+                "NOUN(Stele), TAG(Metal), TAG(Alloy), ID(metal-steel)"
+            ))
+        );
+        assert!(
+            entry_key("TEMPLATE(NOUN[[Metal, Wood]] Bull Pub), TAG(Restaurant)")
+                .eq("TAG(Restaurant), TEMPLATE(NOUN[[Metal, Wood]] Bull Pub)")
+        );
+    }
+
+    #[test]
+    fn test_ids_survive_rebuild() {
+        let before = build_dictionary(vec![
+            "TEMPLATE(NOUN[[Metal]] Bull Pub), TAG(Restaurant), ID(bull-pub)".to_string(),
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal), ID(steel)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+        ]);
+        let after = build_dictionary(vec![
+            "NOUN(Iron),  TAG(Metal)".to_string(),
+            "NOUN(Steel), ADJECTIVE(Steel), TAG(Metal), TAG(Alloy), ID(steel)".to_string(),
+            "TEMPLATE(NOUN[[Metal]] Bull Tavern), TAG(Restaurant), ID(bull-pub)".to_string(),
+            "NOUN(Copper), TAG(Metal)".to_string(),
+        ]);
+        for id in before.words.keys() {
+            assert!(after.words.contains_key(id));
+        }
+        for id in before.templates.keys() {
+            assert!(after.templates.contains_key(id));
+        }
+    }
+
+    #[test]
+    fn test_duplicate_ids() {
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal), ID(steel)".to_string(),
+            "TEMPLATE(The NOUN[Metal] Inn), ID(inn)".to_string(),
+            "NOUN(Steel), TAG(Metal), ID(steel)".to_string(),
+            "NOUN(Iron), TAG(Metal), ID(steel)".to_string(),
+            "TEMPLATE(The NOUN[Metal] Arms), ID(inn)".to_string(),
+            "NOUN(Tin),  TAG(Metal)".to_string(),
+            "TAG(Metal), NOUN(Tin), WEIGHT(2)".to_string(),
+        ]);
+        let messages: Vec<String> = dict.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert!(messages.eq(&vec![
+            "<input>:4:25: warning: duplicate id, replaces the NOUN 'Steel' at <input>:3"
+                .to_string(),
+            "<input>:5:33: warning: duplicate id, replaces the TEMPLATE at <input>:2".to_string(),
+            "<input>:7:13: warning: duplicate id, replaces the NOUN 'Tin' at <input>:6".to_string(),
+        ]));
+        // the later line still wins
+        assert!(dict.words.len().eq(&2));
+        assert!(dict.words.values().any(|w| w.base.eq("Iron")));
+    }
+
+    #[test]
+    fn test_try_build_dictionary() {
+        assert!(try_build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "TEMPLATE(NOUN[[Metal]] Bull Pub), TAG(Restaurant)".to_string(),
+        ])
+        .is_ok());
+        let error = try_build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(St[eel), TAG(Metal)".to_string(),
+        ])
+        .unwrap_err();
+        assert!(matches!(error, TemplaterError::Parse { line: 2, .. }));
+    }
+
+    #[test]
+    fn test_try_build_dictionary_from_folder() {
+        assert!(try_build_dictionary_from_folder("./src/dictionary/data_files").is_ok());
+        assert!(matches!(
+            try_build_dictionary_from_folder("./does/not/exist"),
+            Err(TemplaterError::Io { .. })
+        ));
+    }
+
+    #[test]
+    fn test_build_diagnostics() {
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Iron), FOO(Metal)".to_string(),
+            "TEMPLATE(NOUN[[Metal] Bull Pub), TAG(Restaurant)".to_string(),
+        ]);
+        assert!(dict.words.len().eq(&2));
+        assert!(dict.diagnostics.len().eq(&2));
+        assert!(dict.diagnostics[0].line.eq(&2));
+        assert!(dict.diagnostics[0].message.eq("unknown wrapper FOO"));
+        assert!(dict.diagnostics[1].line.eq(&3));
+        assert!(dict.diagnostics[1]
+            .message
+            .eq("unbalanced brackets in TEMPLATE slot"));
+
+        // a bare value is left out but the rest of its line still loads
+        let bare = build_dictionary(vec!["Aluminium, NOUN(Tin), TAG(Metal)".to_string()]);
+        assert!(bare.words.len().eq(&1));
+        assert!(bare.diagnostics.len().eq(&1));
+        assert!(bare.diagnostics[0].severity.eq(&Severity::Warning));
+        assert!(bare.diagnostics[0]
+            .to_string()
+            .eq("<input>:1:1: warning: unexpected text 'Aluminium'"));
+    }
+
+    #[test]
+    fn test_try_get_random_word() {
+        let dict = build_dictionary(vec!["NOUN(Steel), TAG(Metal)".to_string()]);
+        let mut rng = rand::thread_rng();
+        assert!(dict
+            .try_get_random_word((WordType::Noun, vec![vec!["Metal".to_string()]]), &mut rng)
+            .is_ok());
+        assert!(matches!(
+            dict.try_get_random_word((WordType::Noun, vec![vec!["Wood".to_string()]]), &mut rng),
+            Err(TemplaterError::EmptySlot(_))
+        ));
+        assert!(dict
+            .try_get_random_word_without(
+                Query::noun().all_of(["Metal"]),
+                vec!["Wood".to_string()],
+                &mut rng
+            )
+            .is_ok());
+        assert!(matches!(
+            dict.try_get_random_word_without(
+                Query::noun().all_of(["Metal"]),
+                vec!["Metal".to_string()],
+                &mut rng
+            ),
+            Err(TemplaterError::EmptySlot(_))
+        ));
+        assert!(matches!(
+            dict.try_get_random_template(Query::any().all_of(["Inn"]), &mut rng),
+            Err(TemplaterError::EmptySlot(query)) if query.to_string().eq("Inn")
+        ));
+    }
+
+    #[test]
+    fn test_comments_and_continuations() {
+        let dict = build_dictionary(vec![
+            "\u{feff}# Metals".to_string(),
+            "NOUN(Steel), TAG(Metal) // the default metal\r".to_string(),
+            "".to_string(),
+            "NOUN(Iron), TAG(Metal) # a comment ending in \\".to_string(),
+            "TEMPLATE(The NOUN[[Metal]] \\\r".to_string(),
+            "    Bull Pub), \\".to_string(),
+            "    TAG(Restaurant)".to_string(),
+            "NOUN(Copper), FOO(Metal)".to_string(),
+        ]);
+        assert!(dict.words.len().eq(&3));
+        assert!(dict.templates.len().eq(&1));
+        let template = dict.templates.values().next().unwrap();
+        assert!(template.tags.contains("Restaurant"));
+        assert!(dict
+            .render_template(&template.id, &mut rand::thread_rng())
+            .unwrap()
+            .ends_with(" Bull Pub"));
+        assert!(dict.diagnostics.len().eq(&1));
+        assert!(dict.diagnostics[0].line.eq(&8));
+    }
+
+    #[test]
+    fn test_includes_and_recursive_folders() {
+        let root = std::env::temp_dir().join(format!("procgen_includes_{}", std::process::id()));
+        let write = |path: &str, text: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        };
+        write(
+            "main.csv",
+            "INCLUDE(shared/metals.csv)\nNOUN(Oak), TAG(Wood)\nINCLUDE(nested/deep)",
+        );
+        write(
+            "shared/metals.csv",
+            "NOUN(Steel), TAG(Metal)\nINCLUDE(../main.csv)",
+        );
+        write("shared/notes.txt", "NOUN(Ignored), TAG(Metal)");
+        write("nested/deep/gems.csv", "NOUN(Ruby), TAG(Gem)");
+        let folder = root.to_str().unwrap();
+        let options = LoadOptions {
+            recursive: false,
+            extensions: vec!["csv".to_string()],
+        };
+
+        let dict = build_dictionary_from_folder_with(folder, &options);
+        let mut words: Vec<String> = dict.words.values().map(|w| w.base.clone()).collect();
+        words.sort();
+        assert!(words.eq(&vec!["Oak", "Ruby", "Steel"]));
+        assert!(dict.diagnostics.len().eq(&1));
+        assert!(dict.diagnostics[0].source.ends_with("metals.csv"));
+        assert!(dict.diagnostics[0].line.eq(&2));
+        assert!(dict.diagnostics[0]
+            .message
+            .eq("INCLUDE of '../main.csv' forms a cycle and is skipped"));
+
+        let everything = build_dictionary_from_folder(folder);
+        assert!(everything.words.len().eq(&4));
+        assert!(everything.diagnostics.len().eq(&1));
+
+        write("main.csv", "INCLUDE(missing.csv)");
+        let error = try_build_dictionary_from_folder_with(folder, &options).unwrap_err();
+        assert!(error.to_string().contains("cannot include 'missing.csv'"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_deep_tag_hierarchy() {
+        // declared leaf first so no ordering of the HAS_PARENT lines can help
+        let dict = build_dictionary(vec![
+            "NOUN(Oak), TAG(Oak)".to_string(),
+            "TEMPLATE(NOUN[[Entity]] Inn), TAG(Oak)".to_string(),
+            "TAG(Oak), HAS_PARENT(Tree)".to_string(),
+            "TAG(Tree), HAS_PARENT(Wood)".to_string(),
+            "TAG(Wood), HAS_PARENT(Material)".to_string(),
+            "TAG(Material), HAS_PARENT(Matter)".to_string(),
+            "TAG(Matter), HAS_PARENT(Thing)".to_string(),
+            "TAG(Thing), HAS_PARENT(Entity)".to_string(),
+            "TAG(Tree), HAS_PARENT(Plant)".to_string(),
+        ]);
+        assert!(dict.diagnostics.is_empty());
+        let oak = dict.words.values().next().unwrap();
+        for tag in [
+            "Oak", "Tree", "Wood", "Material", "Matter", "Thing", "Entity", "Plant",
+        ] {
+            assert!(oak.tags.contains(tag));
+            assert!(dict
+                .index
+                .tag_words
+                .get(&(WordType::Noun, tag.to_string()))
+                .unwrap()
+                .contains(&oak.id));
+            assert!(dict.index.tag_templates.get(tag).unwrap().len().eq(&1));
+        }
+        assert!(dict.index.tag_ancestors.get("Oak").unwrap().len().eq(&7));
+        assert!(dict.index.tag_ancestors.get("Thing").unwrap().len().eq(&1));
+        assert!(!dict.index.tag_ancestors.contains_key("Entity"));
+    }
+
+    #[test]
+    fn test_tag_hierarchy_cycle() {
+        let dict = build_dictionary(vec![
+            "NOUN(Lead), TAG(Metal)".to_string(),
+            "TAG(Metal), HAS_PARENT(Mineral)".to_string(),
+            "TAG(Mineral), HAS_PARENT(Rock)".to_string(),
+            "TAG(Rock), HAS_PARENT(Metal), HAS_PARENT(Earth)".to_string(),
+        ]);
+        assert!(dict.diagnostics.len().eq(&1));
+        assert!(dict.diagnostics[0].severity.eq(&Severity::Warning));
+        assert!(dict.diagnostics[0].line.eq(&4));
+        assert!(dict.diagnostics[0]
+            .message
+            .eq("tag hierarchy cycle between Metal, Mineral, Rock"));
+        let lead = dict.words.values().next().unwrap();
+        assert!(lead.tags.len().eq(&4));
+        assert!(!dict
+            .index
+            .tag_ancestors
+            .get("Rock")
+            .unwrap()
+            .contains("Rock"));
+    }
+
+    #[test]
+    fn test_template_cycle() {
+        let dict = build_dictionary(vec![
+            "NOUN(Ale), TAG(Drink)".to_string(),
+            "TEMPLATE(The TEMPLATE[Sign] Inn), TAG(Tavern)".to_string(),
+            "TEMPLATE(NOUN[Drink] and TEMPLATE[Tavern]), TAG(Sign)".to_string(),
+            "TEMPLATE(NOUN[Drink] Hall), TAG(Sign)".to_string(),
+            "TEMPLATE(TEMPLATE[Sign] Road)".to_string(),
+        ]);
+        assert!(dict.diagnostics.len().eq(&1));
+        assert!(dict.diagnostics[0].severity.eq(&Severity::Warning));
+        assert!(dict.diagnostics[0].line.eq(&3));
+        assert_eq!(dict.diagnostics[0].span, 0..42);
+        assert!(dict.diagnostics[0]
+            .message
+            .eq("nested TEMPLATE cycle through <input>:2, <input>:3"));
+    }
+
+    #[test]
+    fn test_template_name_diagnostics() {
+        let dict = build_dictionary(vec![
+            "TEMPLATE(The NOUN[Animal] Inn), NAME(Inn)".to_string(),
+            "TEMPLATE(NAME[Inn] and NAME[Hall])".to_string(),
+            "TEMPLATE(The NOUN[Drink] Inn), NAME(Inn)".to_string(),
+            "TEMPLATE(The NOUN[Animal] Inn), NAME(Inn)".to_string(),
+        ]);
+        let messages: Vec<String> = dict.diagnostics.iter().map(|d| d.to_string()).collect();
+        assert!(messages.eq(&vec![
+            "<input>:3:32: warning: duplicate NAME 'Inn', already used by the template at <input>:1"
+                .to_string(),
+            "<input>:2:1: warning: no template is named 'Hall'".to_string(),
+        ]));
+        let named = dict.template_named("Inn").unwrap();
+        assert!(named.template[1].eq(&TemplateElement::Slot(
+            "NOUN[Animal]".parse::<Query>().unwrap()
+        )));
+    }
+
+    #[test]
+    fn test_unmatched_group_matches_nothing() {
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Oak), TAG(Wood)".to_string(),
+            "TEMPLATE(NOUN[[Metal]] Inn), TAG(Tavern)".to_string(),
+        ]);
+        let mut rng = rand::thread_rng();
+        let groups = |groups: Vec<Vec<&str>>| -> Vec<Vec<String>> {
+            return groups
+                .into_iter()
+                .map(|g| g.into_iter().map(|t| t.to_string()).collect())
+                .collect();
+        };
+        for _ in 0..20 {
+            // a first group matching nothing used to be read as "no filter yet"
+            let unknown_first = groups(vec![vec!["UnknownTag"], vec!["Metal"]]);
+            assert!(dict
+                .get_random_word((WordType::Noun, unknown_first.clone()), &mut rng)
+                .is_none());
+            assert!(dict
+                .get_random_word_without((WordType::Noun, unknown_first.clone()), vec![], &mut rng)
+                .is_none());
+            assert!(dict
+                .get_random_template(groups(vec![vec!["Unknown"], vec!["Tavern"]]), &mut rng)
+                .is_none());
+
+            // and an empty intersection used to be refilled by the next group
+            let disjoint = groups(vec![vec!["Metal"], vec!["Wood"], vec!["Metal"]]);
+            assert!(dict
+                .get_random_word((WordType::Noun, disjoint.clone()), &mut rng)
+                .is_none());
+            assert!(dict
+                .get_random_word_without((WordType::Noun, disjoint), vec![], &mut rng)
+                .is_none());
+            assert!(dict
+                .get_random_template(
+                    groups(vec![vec!["Tavern"], vec!["Shop"], vec!["Tavern"]]),
+                    &mut rng
+                )
+                .is_none());
+
+            assert!(dict
+                .get_random_word((WordType::Noun, vec![]), &mut rng)
+                .is_none());
+            assert!(dict
+                .get_random_word_without(
+                    (WordType::Noun, groups(vec![vec!["Metal", "Wood"]])),
+                    vec!["Wood".to_string()],
+                    &mut rng
+                )
+                .unwrap()
+                .base
+                .eq("Steel"));
+        }
+        assert!(matches!(
+            dict.try_get_random_word(
+                (
+                    WordType::Noun,
+                    groups(vec![vec!["UnknownTag"], vec!["Metal"]])
+                ),
+                &mut rng
+            ),
+            Err(TemplaterError::EmptySlot(_))
+        ));
     }
 }
@@ -0,0 +1,43 @@
+#[allow(clippy::module_inception)]
+pub mod diagnostic {
+    use std::{fmt, ops::Range};
+
+    #[derive(PartialEq, Debug, Clone, Copy, Hash, Eq, PartialOrd, Ord)]
+    pub enum Severity {
+        Warning,
+        Error,
+    }
+
+    #[derive(PartialEq, Debug, Clone)]
+    pub struct Diagnostic {
+        pub severity: Severity,
+        pub source: String,
+        pub line: usize,
+        // character columns of the offending text, zero based and end exclusive
+        pub span: Range<usize>,
+        pub message: String,
+    }
+
+    impl fmt::Display for Severity {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Severity::Warning => write!(f, "warning"),
+                Severity::Error => write!(f, "error"),
+            }
+        }
+    }
+
+    impl fmt::Display for Diagnostic {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{}:{}:{}: {}: {}",
+                self.source,
+                self.line,
+                self.span.start + 1,
+                self.severity,
+                self.message
+            )
+        }
+    }
+}
@@ -0,0 +1,207 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
+pub mod index {
+    use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+    use crate::dictionary::dictionary::{Dictionary, Index};
+
+    impl Index {
+        pub fn tags(self: &Self) -> &BTreeSet<String> {
+            return &self.all_tags;
+        }
+
+        pub fn parents(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self
+                .tag_children
+                .iter()
+                .filter(|(_, children)| children.contains(tag))
+                .map(|(parent, _)| parent.clone())
+                .collect();
+        }
+
+        pub fn children(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self.tag_children.get(tag).cloned().unwrap_or_default();
+        }
+
+        pub fn ancestors(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self.tag_ancestors.get(tag).cloned().unwrap_or_default();
+        }
+
+        pub fn descendants(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self
+                .tag_ancestors
+                .iter()
+                .filter(|(_, ancestors)| ancestors.contains(tag))
+                .map(|(child, _)| child.clone())
+                .collect();
+        }
+
+        // tags with no parent
+        pub fn roots(self: &Self) -> BTreeSet<String> {
+            return self
+                .all_tags
+                .iter()
+                .filter(|tag| self.is_root(tag))
+                .cloned()
+                .collect();
+        }
+
+        // tags with no children
+        pub fn leaves(self: &Self) -> BTreeSet<String> {
+            return self
+                .all_tags
+                .iter()
+                .filter(|tag| self.tag_children.get(*tag).map_or(true, |c| c.is_empty()))
+                .cloned()
+                .collect();
+        }
+
+        // the fewest HAS_PARENT links between the tag and a root, None for unknown tags
+        // and for tags whose every ancestor sits in a cycle
+        pub fn depth(self: &Self, tag: &str) -> Option<usize> {
+            if !self.all_tags.contains(tag) {
+                return None;
+            }
+            return self
+                .upward_paths(tag)
+                .into_iter()
+                .filter(|(ancestor, _)| self.is_root(ancestor))
+                .map(|(_, path)| path.len() - 1)
+                .min();
+        }
+
+        fn is_root(self: &Self, tag: &str) -> bool {
+            return self.tag_ancestors.get(tag).map_or(true, |a| a.is_empty());
+        }
+
+        // the shortest chain of tags from one tag to the other, following HAS_PARENT links
+        // up when `to` is an ancestor of `from` or down when it is a descendant
+        pub fn path_between(self: &Self, from: &str, to: &str) -> Option<Vec<String>> {
+            if let Some(path) = self.upward_paths(from).remove(to) {
+                return Some(path);
+            }
+            let mut path = self.upward_paths(to).remove(from)?;
+            path.reverse();
+            return Some(path);
+        }
+
+        // breadth first walk up from a tag, giving the shortest path to each ancestor
+        fn upward_paths(self: &Self, tag: &str) -> BTreeMap<String, Vec<String>> {
+            let mut output: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            let mut queue: VecDeque<Vec<String>> = VecDeque::new();
+            queue.push_back(vec![tag.to_string()]);
+            while let Some(path) = queue.pop_front() {
+                let last = path.last().unwrap().clone();
+                if output.contains_key(&last) {
+                    continue;
+                }
+                for parent in self.parents(&last) {
+                    let mut next = path.clone();
+                    next.push(parent);
+                    queue.push_back(next);
+                }
+                output.insert(last, path);
+            }
+            return output;
+        }
+    }
+
+    // the same queries straight from the dictionary
+    impl Dictionary {
+        pub fn tags(self: &Self) -> &BTreeSet<String> {
+            return self.index.tags();
+        }
+
+        pub fn parents(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self.index.parents(tag);
+        }
+
+        pub fn children(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self.index.children(tag);
+        }
+
+        pub fn ancestors(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self.index.ancestors(tag);
+        }
+
+        pub fn descendants(self: &Self, tag: &str) -> BTreeSet<String> {
+            return self.index.descendants(tag);
+        }
+
+        pub fn roots(self: &Self) -> BTreeSet<String> {
+            return self.index.roots();
+        }
+
+        pub fn leaves(self: &Self) -> BTreeSet<String> {
+            return self.index.leaves();
+        }
+
+        pub fn depth(self: &Self, tag: &str) -> Option<usize> {
+            return self.index.depth(tag);
+        }
+
+        pub fn path_between(self: &Self, from: &str, to: &str) -> Option<Vec<String>> {
+            return self.index.path_between(from, to);
+        }
+    }
+
+    #[test]
+    fn test_tag_hierarchy_queries() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "NOUN(Oak), TAG(Oak)".to_string(),
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "TAG(Oak), TAG(Birch), HAS_PARENT(Tree)".to_string(),
+            "TAG(Tree), HAS_PARENT(Wood), HAS_PARENT(Plant)".to_string(),
+            "TAG(Wood), TAG(Metal), HAS_PARENT(Material)".to_string(),
+            "TAG(Material), HAS_PARENT(Thing)".to_string(),
+        ]);
+        let index = &dict.index;
+        let set = |tags: Vec<&str>| -> BTreeSet<String> {
+            return tags.into_iter().map(|t| t.to_string()).collect();
+        };
+        assert!(index
+            .ancestors("Oak")
+            .eq(&set(vec!["Tree", "Wood", "Plant", "Material", "Thing"])));
+        assert!(index.parents("Tree").eq(&set(vec!["Wood", "Plant"])));
+        assert!(index.children("Tree").eq(&set(vec!["Oak", "Birch"])));
+        assert!(index
+            .descendants("Material")
+            .eq(&set(vec!["Wood", "Metal", "Tree", "Oak", "Birch"])));
+        assert!(index.roots().eq(&set(vec!["Plant", "Thing"])));
+        assert!(index.leaves().eq(&set(vec!["Oak", "Birch", "Metal"])));
+        assert!(index.depth("Thing").eq(&Some(0)));
+        assert!(index.depth("Oak").eq(&Some(2)));
+        assert!(index.depth("Metal").eq(&Some(2)));
+        assert!(index.depth("Unknown").is_none());
+        assert!(index.path_between("Oak", "Thing").eq(&Some(vec![
+            "Oak".to_string(),
+            "Tree".to_string(),
+            "Wood".to_string(),
+            "Material".to_string(),
+            "Thing".to_string()
+        ])));
+        assert!(index.path_between("Material", "Birch").eq(&Some(vec![
+            "Material".to_string(),
+            "Wood".to_string(),
+            "Tree".to_string(),
+            "Birch".to_string()
+        ])));
+        assert!(index
+            .path_between("Oak", "Oak")
+            .eq(&Some(vec!["Oak".to_string()])));
+        assert!(index.path_between("Oak", "Metal").is_none());
+        assert!(index.tags().len().eq(&8));
+
+        assert!(dict.roots().eq(&index.roots()));
+        assert!(dict.leaves().eq(&set(vec!["Oak", "Birch", "Metal"])));
+        assert!(dict.depth("Birch").eq(&Some(2)));
+        assert!(dict.descendants("Tree").eq(&set(vec!["Oak", "Birch"])));
+        assert!(dict
+            .path_between("Metal", "Thing")
+            .eq(&index.path_between("Metal", "Thing")));
+    }
+}
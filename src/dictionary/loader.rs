@@ -0,0 +1,123 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
+pub mod loader {
+    use std::{
+        fs::{self, File},
+        io::{self, BufRead},
+        path::{Path, PathBuf},
+    };
+
+    use crate::{dictionary::parser::parser::logical_lines, error::error::TemplaterError};
+
+    #[derive(PartialEq, Debug, Clone)]
+    pub struct LoadOptions {
+        // descend into sub folders, otherwise they are skipped
+        pub recursive: bool,
+        // extensions to load without the leading '.', empty loads every file
+        pub extensions: Vec<String>,
+    }
+
+    impl Default for LoadOptions {
+        fn default() -> Self {
+            return LoadOptions {
+                recursive: true,
+                extensions: Vec::new(),
+            };
+        }
+    }
+
+    impl LoadOptions {
+        pub fn accepts(self: &Self, path: &Path) -> bool {
+            if self.extensions.is_empty() {
+                return true;
+            }
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            return self
+                .extensions
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(extension));
+        }
+    }
+
+    fn io_error(path: &Path, source: io::Error) -> TemplaterError {
+        return TemplaterError::Io {
+            path: path.to_string_lossy().to_string(),
+            source,
+        };
+    }
+
+    // every data file under the folder, sorted by path so load order is reproducible
+    pub fn list_folder(
+        folder_path: &Path,
+        options: &LoadOptions,
+    ) -> Result<Vec<PathBuf>, TemplaterError> {
+        let mut output: Vec<PathBuf> = Vec::new();
+        for entry in fs::read_dir(folder_path).map_err(|e| io_error(folder_path, e))? {
+            let path = entry.map_err(|e| io_error(folder_path, e))?.path();
+            if path.is_dir() {
+                if options.recursive {
+                    output.extend(list_folder(&path, options)?);
+                }
+            } else if options.accepts(&path) {
+                output.push(path);
+            }
+        }
+        output.sort();
+        return Ok(output);
+    }
+
+    pub fn read_source(path: &Path) -> Result<Vec<(usize, String)>, TemplaterError> {
+        let data = File::open(path).map_err(|e| io_error(path, e))?;
+        let mut lines: Vec<(usize, String)> = Vec::new();
+        for (i, line) in io::BufReader::new(data).lines().enumerate() {
+            lines.push((i + 1, line.map_err(|e| io_error(path, e))?));
+        }
+        return Ok(logical_lines(lines));
+    }
+
+    // INCLUDE paths are relative to the including file, inline lines to the working directory
+    pub fn resolve_include(source: &str, target: &str) -> PathBuf {
+        let parent = Path::new(source).parent().unwrap_or(Path::new(""));
+        return parent.join(target);
+    }
+
+    #[test]
+    fn test_list_folder() {
+        let all = list_folder(
+            Path::new("./src/dictionary/data_files"),
+            &LoadOptions::default(),
+        )
+        .unwrap();
+        assert!(all.len().eq(&6));
+        assert!(all.windows(2).all(|w| w[0] < w[1]));
+
+        let none = list_folder(
+            Path::new("./src/dictionary"),
+            &LoadOptions {
+                recursive: false,
+                extensions: vec!["csv".to_string()],
+            },
+        )
+        .unwrap();
+        assert!(none.is_empty());
+        let nested = list_folder(
+            Path::new("./src/dictionary"),
+            &LoadOptions {
+                recursive: true,
+                extensions: vec!["CSV".to_string()],
+            },
+        )
+        .unwrap();
+        assert!(nested.eq(&all));
+    }
+
+    #[test]
+    fn test_resolve_include() {
+        assert!(resolve_include("data/names.csv", "places/towns.csv")
+            .eq(Path::new("data/places/towns.csv")));
+        assert!(resolve_include("<input>", "data/names.csv").eq(Path::new("data/names.csv")));
+    }
+}
@@ -0,0 +1,1725 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
+pub mod parser {
+    use std::ops::Range;
+
+    use crate::dictionary::{
+        diagnostic::diagnostic::{Diagnostic, Severity},
+        dictionary::{
+            ADJECTIVE_WRAPPER, ID_WRAPPER, INCLUDE_WRAPPER, INLINE_SOURCE, NAME_WRAPPER,
+            NOUN_WRAPPER, RANK_WRAPPER, RARITY_WRAPPER, TAG_PARENT_WRAPPER, TAG_WRAPPER,
+            WEIGHT_WRAPPER,
+        },
+        query::query::{Query, QueryExpr},
+        rarity::rarity::Rarity,
+        template::template::{TemplateElement, TEMPLATE_WRAPPER},
+        word::word::{WordRelationType, WordType},
+    };
+
+    // character columns, zero based and end exclusive
+    pub type Span = Range<usize>;
+
+    #[derive(PartialEq, Debug, Clone)]
+    pub enum Token {
+        Ident(String),
+        Space(String),
+        LParen,
+        RParen,
+        LBracket,
+        RBracket,
+        Comma,
+        Symbol(char),
+        // a character preceded by '\', never treated as syntax
+        Escaped(char),
+    }
+
+    #[derive(PartialEq, Debug, Clone)]
+    pub struct SpannedToken {
+        pub token: Token,
+        pub span: Span,
+    }
+
+    #[derive(PartialEq, Debug, Clone)]
+    pub enum Entry {
+        Noun(String),
+        Adjective(String),
+        Tag(String),
+        HasParent(String),
+        Id(String),
+        Include(String),
+        Weight(f64),
+        Rarity(Rarity),
+        Rank(u64),
+        Name(String),
+        Template(Vec<TemplateElement>),
+    }
+
+    #[derive(PartialEq, Debug, Clone)]
+    pub struct ParsedLine {
+        pub entries: Vec<(Entry, Span)>,
+        // stable identity of the line, see dictionary::entry_key
+        pub key: String,
+        pub diagnostics: Vec<Diagnostic>,
+    }
+
+    impl Token {
+        fn source(self: &Self) -> String {
+            return match self {
+                Token::Ident(s) | Token::Space(s) => s.clone(),
+                Token::LParen => "(".to_string(),
+                Token::RParen => ")".to_string(),
+                Token::LBracket => "[".to_string(),
+                Token::RBracket => "]".to_string(),
+                Token::Comma => ",".to_string(),
+                Token::Symbol(c) | Token::Escaped(c) => c.to_string(),
+            };
+        }
+
+        // text that can appear in a value or template without escaping
+        fn literal(self: &Self) -> Option<String> {
+            return match self {
+                Token::Ident(s) | Token::Space(s) => Some(s.clone()),
+                Token::Escaped(c) => Some(c.to_string()),
+                Token::Symbol(c) if !c.eq(&'\\') => Some(c.to_string()),
+                _ => None,
+            };
+        }
+
+        // text that can appear in a tag name
+        fn tag_literal(self: &Self) -> Option<String> {
+            return match self {
+                Token::Ident(s) => Some(s.clone()),
+                Token::Escaped(c) => Some(c.to_string()),
+                Token::Symbol(c) if TAG_PUNCTUATION.contains(*c) => Some(c.to_string()),
+                _ => None,
+            };
+        }
+    }
+
+    // characters that must be written as '\x' inside values and template text,
+    // '/' only starts a comment when doubled and '@' only names a binding before a name,
+    // but both are escaped anywhere for simplicity
+    pub static RESERVED_CHARACTERS: &str = "()[],\\#/@";
+
+    pub fn escape(text: &str) -> String {
+        let mut output = String::new();
+        for c in text.chars() {
+            if RESERVED_CHARACTERS.contains(c) {
+                output.push('\\');
+            }
+            output.push(c);
+        }
+        return output;
+    }
+
+    // punctuation allowed inside tag names alongside letters, digits and '_'
+    static TAG_PUNCTUATION: &str = "-'.";
+
+    fn is_ident_char(c: char) -> bool {
+        return c.is_alphanumeric() || c.eq(&'_');
+    }
+
+    fn invalid_character(token: &Token, context: &str) -> String {
+        return match token {
+            Token::Symbol('\\') => format!("dangling '\\' in {}", context),
+            Token::LParen | Token::RParen | Token::LBracket | Token::RBracket | Token::Comma => {
+                format!(
+                    "invalid character '{0}' in {1}, escape it as '\\{0}'",
+                    token.source(),
+                    context
+                )
+            }
+            _ => format!("invalid character '{}' in {}", token.source(), context),
+        };
+    }
+
+    // where the code part of a line ends, and whether it ends in a dangling '\'
+    fn scan_line(chars: &[char]) -> (usize, bool) {
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '\\' if i + 1 == chars.len() => return (chars.len(), true),
+                '\\' => i += 2,
+                '#' => return (i, false),
+                '/' if chars.get(i + 1).eq(&Some(&'/')) => return (i, false),
+                _ => i += 1,
+            }
+        }
+        return (chars.len(), false);
+    }
+
+    // joins physical lines into entries: strips CR and a leading BOM, follows trailing '\'
+    // continuations (keeping the first line number) and drops blank and comment-only lines
+    pub fn logical_lines(lines: Vec<(usize, String)>) -> Vec<(usize, String)> {
+        let mut output: Vec<(usize, String)> = Vec::new();
+        let mut pending: Option<(usize, String)> = None;
+        for (i, (line_number, line)) in lines.into_iter().enumerate() {
+            let mut line = line.trim_end().to_string();
+            if i == 0 {
+                line = line.trim_start_matches('\u{feff}').to_string();
+            }
+            let chars: Vec<char> = line.chars().collect();
+            let (end, continued) = scan_line(&chars);
+            let code: String = chars[..end].iter().collect();
+            let (number, mut text) = match pending.take() {
+                Some((number, text)) => (number, text + code.trim_start()),
+                None => (line_number, code),
+            };
+            if continued {
+                text.pop();
+                pending = Some((number, text));
+            } else if !text.trim().is_empty() {
+                output.push((number, text));
+            }
+        }
+        if let Some((number, text)) = pending {
+            if !text.trim().is_empty() {
+                output.push((number, text));
+            }
+        }
+        return output;
+    }
+
+    pub fn tokenize(line: &str) -> Vec<SpannedToken> {
+        let all_chars: Vec<char> = line.chars().collect();
+        let (end, _) = scan_line(&all_chars);
+        let chars = &all_chars[..end];
+        let mut output: Vec<SpannedToken> = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let start = i;
+            let c = chars[i];
+            let token = if c.eq(&'\\') && i + 1 < chars.len() {
+                i += 2;
+                Token::Escaped(chars[i - 1])
+            } else if is_ident_char(c) {
+                while i < chars.len() && is_ident_char(chars[i]) {
+                    i += 1;
+                }
+                Token::Ident(chars[start..i].iter().collect())
+            } else if c.is_whitespace() {
+                while i < chars.len() && chars[i].is_whitespace() {
+                    i += 1;
+                }
+                Token::Space(chars[start..i].iter().collect())
+            } else {
+                i += 1;
+                match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    ',' => Token::Comma,
+                    _ => Token::Symbol(c),
+                }
+            };
+            output.push(SpannedToken {
+                token,
+                span: start..i,
+            });
+        }
+        return output;
+    }
+
+    pub fn parse_line(source: &str, line_number: usize, line: &str) -> ParsedLine {
+        let mut parser = Parser {
+            tokens: tokenize(line),
+            pos: 0,
+            source,
+            line_number,
+            diagnostics: Vec::new(),
+            open_paren: 0..0,
+        };
+        if parser
+            .tokens
+            .iter()
+            .all(|t| matches!(t.token, Token::Space(_)))
+        {
+            return ParsedLine {
+                entries: Vec::new(),
+                key: String::new(),
+                diagnostics: Vec::new(),
+            };
+        }
+        let entries = parser.parse_entries();
+        let chars: Vec<char> = line.chars().collect();
+        let key = line_key(&chars, &entries);
+        let entries: Vec<(Entry, Span)> = entries
+            .into_iter()
+            .filter_map(|(entry, span, _)| entry.map(|e| (e, span)))
+            .collect();
+        return ParsedLine {
+            entries,
+            key,
+            diagnostics: parser.diagnostics,
+        };
+    }
+
+    // a query on its own, either a bare expression such as 'Metal & !Cursed' matching
+    // any word type or in slot form such as 'NOUN[Metal & !Cursed]'
+    pub fn parse_query(text: &str) -> (Option<Query>, Vec<Diagnostic>) {
+        let mut parser = Parser {
+            tokens: tokenize(text),
+            pos: 0,
+            source: INLINE_SOURCE,
+            line_number: 1,
+            diagnostics: Vec::new(),
+            open_paren: 0..0,
+        };
+        parser.skip_space();
+        let query = match (parser.peek(), parser.peek_at(1)) {
+            (Some(Token::Ident(keyword)), Some(Token::LBracket))
+                if keyword.eq(NOUN_WRAPPER) || keyword.eq(ADJECTIVE_WRAPPER) =>
+            {
+                parser.parse_slot()
+            }
+            _ => parser.parse_query_expr().map(|expr| Query {
+                word_type: None,
+                expr,
+            }),
+        };
+        parser.skip_space();
+        if query.is_some() && parser.peek().is_some() {
+            let span = parser.span().start..text.chars().count();
+            let rest: String = text.chars().skip(span.start).collect();
+            parser.report(
+                Severity::Error,
+                span,
+                format!("unexpected text '{}' in query", rest.trim()),
+            );
+            return (None, parser.diagnostics);
+        }
+        return (query, parser.diagnostics);
+    }
+
+    // an explicit ID wins, otherwise every entry with its whitespace collapsed, sorted.
+    // Weights, rarities and ranks are tuning rather than identity so they are left out.
+    fn line_key(chars: &[char], entries: &[(Option<Entry>, Span, String)]) -> String {
+        for (entry, _, _) in entries {
+            if let Some(Entry::Id(id)) = entry {
+                return id.clone();
+            }
+        }
+        let mut parts: Vec<String> = entries
+            .iter()
+            .filter(|(_, _, name)| {
+                ![WEIGHT_WRAPPER, RARITY_WRAPPER, RANK_WRAPPER].contains(&name.as_str())
+            })
+            .map(|(_, span, _)| {
+                let text: String = chars[span.clone()].iter().collect();
+                text.split_whitespace().collect::<Vec<&str>>().join(" ")
+            })
+            .filter(|part| !part.is_empty())
+            .collect();
+        parts.sort();
+        return parts.join(", ");
+    }
+
+    struct Parser<'a> {
+        tokens: Vec<SpannedToken>,
+        pos: usize,
+        source: &'a str,
+        line_number: usize,
+        diagnostics: Vec<Diagnostic>,
+        // the '(' of the entry being parsed, for reporting unclosed entries
+        open_paren: Span,
+    }
+
+    impl<'a> Parser<'a> {
+        fn peek(self: &Self) -> Option<&Token> {
+            return self.tokens.get(self.pos).map(|t| &t.token);
+        }
+
+        fn peek_at(self: &Self, offset: usize) -> Option<&Token> {
+            return self.tokens.get(self.pos + offset).map(|t| &t.token);
+        }
+
+        fn span(self: &Self) -> Span {
+            return match self.tokens.get(self.pos) {
+                Some(t) => t.span.clone(),
+                None => {
+                    let end = self.tokens.last().map(|t| t.span.end).unwrap_or(0);
+                    end..end
+                }
+            };
+        }
+
+        fn bump(self: &mut Self) -> Option<SpannedToken> {
+            let token = self.tokens.get(self.pos).cloned();
+            if token.is_some() {
+                self.pos += 1;
+            }
+            return token;
+        }
+
+        fn skip_space(self: &mut Self) {
+            while let Some(Token::Space(_)) = self.peek() {
+                self.pos += 1;
+            }
+        }
+
+        fn report(self: &mut Self, severity: Severity, span: Span, message: String) {
+            self.diagnostics.push(Diagnostic {
+                severity,
+                source: self.source.to_string(),
+                line: self.line_number,
+                span,
+                message,
+            });
+        }
+
+        fn report_unclosed(self: &mut Self) {
+            let span = self.open_paren.clone();
+            self.report(Severity::Error, span, "unclosed '('".to_string());
+        }
+
+        // skips to just past the ')' closing a paren that has already been consumed
+        fn recover_to_close(self: &mut Self) {
+            let mut depth = 1;
+            while let Some(t) = self.bump() {
+                match t.token {
+                    Token::LParen => depth += 1,
+                    Token::RParen => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        // skips to the next top level comma, leaving it unconsumed
+        fn recover_to_comma(self: &mut Self) {
+            let mut depth = 0;
+            while let Some(token) = self.peek() {
+                match token {
+                    Token::LParen => depth += 1,
+                    Token::RParen if depth > 0 => depth -= 1,
+                    Token::Comma if depth == 0 => return,
+                    _ => {}
+                }
+                self.pos += 1;
+            }
+        }
+
+        // line := entry (',' entry)*
+        fn parse_entries(self: &mut Self) -> Vec<(Option<Entry>, Span, String)> {
+            let mut output: Vec<(Option<Entry>, Span, String)> = Vec::new();
+            loop {
+                self.skip_space();
+                let start = self.span().start;
+                let (entry, name) = self.parse_entry();
+                let mut end = start;
+                for t in &self.tokens[..self.pos] {
+                    if !matches!(t.token, Token::Space(_)) {
+                        end = end.max(t.span.end);
+                    }
+                }
+                if end.eq(&start) {
+                    self.report(Severity::Warning, start..start, "empty entry".to_string());
+                }
+                output.push((entry, start..end, name));
+                self.skip_space();
+                match self.bump() {
+                    None => break,
+                    Some(SpannedToken {
+                        token: Token::Comma,
+                        ..
+                    }) => continue,
+                    Some(t) => {
+                        let name = output.last().unwrap().2.clone();
+                        self.report(
+                            Severity::Error,
+                            t.span.start..t.span.end,
+                            format!("unexpected text after {}(...)", name),
+                        );
+                        self.recover_to_comma();
+                        if self.bump().is_none() {
+                            break;
+                        }
+                    }
+                }
+            }
+            self.check_entries(&output);
+            return output;
+        }
+
+        // entry := IDENT '(' content ')'
+        fn parse_entry(self: &mut Self) -> (Option<Entry>, String) {
+            let name = match (self.peek(), self.peek_at(1)) {
+                (Some(Token::Ident(name)), Some(Token::LParen)) => name.clone(),
+                (None, _) | (Some(Token::Comma), _) => return (None, String::new()),
+                _ => {
+                    self.parse_stray_text();
+                    return (None, String::new());
+                }
+            };
+            let name_span = self.span();
+            self.bump();
+            self.open_paren = self.span();
+            self.bump();
+            let entry = if name.eq(NOUN_WRAPPER) {
+                self.parse_value(&name).map(Entry::Noun)
+            } else if name.eq(ADJECTIVE_WRAPPER) {
+                self.parse_value(&name).map(Entry::Adjective)
+            } else if name.eq(TAG_WRAPPER) {
+                self.parse_tag_value(&name).map(Entry::Tag)
+            } else if name.eq(TAG_PARENT_WRAPPER) {
+                self.parse_tag_value(&name).map(Entry::HasParent)
+            } else if name.eq(ID_WRAPPER) {
+                self.parse_raw().map(Entry::Id)
+            } else if name.eq(INCLUDE_WRAPPER) {
+                self.parse_value(&name).map(Entry::Include)
+            } else if name.eq(WEIGHT_WRAPPER) {
+                self.parse_converted(&name, "a positive number", |v| {
+                    v.parse::<f64>().ok().filter(|n| n.is_finite() && *n > 0.0)
+                })
+                .map(Entry::Weight)
+            } else if name.eq(NAME_WRAPPER) {
+                self.parse_tag_value(&name).map(Entry::Name)
+            } else if name.eq(RARITY_WRAPPER) {
+                self.parse_converted(
+                    &name,
+                    "one of Common, Uncommon, Rare or Legendary",
+                    Rarity::from_name,
+                )
+                .map(Entry::Rarity)
+            } else if name.eq(RANK_WRAPPER) {
+                self.parse_converted(&name, "a whole number from 1", |v| {
+                    v.parse::<u64>().ok().filter(|n| *n > 0)
+                })
+                .map(Entry::Rank)
+            } else if name.eq(TEMPLATE_WRAPPER) {
+                self.parse_template_body().map(Entry::Template)
+            } else {
+                self.report(
+                    Severity::Error,
+                    name_span,
+                    format!("unknown wrapper {}", name),
+                );
+                self.recover_to_close();
+                return (None, String::new());
+            };
+            return (entry, name);
+        }
+
+        fn parse_stray_text(self: &mut Self) {
+            let start = self.pos;
+            let mut depth = 0;
+            while let Some(token) = self.peek() {
+                match token {
+                    Token::LParen => depth += 1,
+                    Token::RParen if depth > 0 => depth -= 1,
+                    Token::RParen => {
+                        let span = self.span();
+                        self.report(Severity::Error, span, "unmatched ')'".to_string());
+                    }
+                    Token::Comma if depth == 0 => break,
+                    _ => {}
+                }
+                self.pos += 1;
+            }
+            let text: String = self.tokens[start..self.pos]
+                .iter()
+                .map(|t| t.token.source())
+                .collect();
+            let first = self.tokens[start].span.start;
+            let last = self.tokens[self.pos - 1].span.end;
+            self.report(
+                Severity::Warning,
+                first..last,
+                format!("unexpected text '{}'", text.trim()),
+            );
+            if depth > 0 {
+                self.report(Severity::Error, first..last, "unclosed '('".to_string());
+            }
+        }
+
+        // value := LITERAL* ')'
+        fn parse_value(self: &mut Self, name: &str) -> Option<String> {
+            let start = self.span();
+            let mut value = String::new();
+            loop {
+                let span = self.span();
+                match self.peek().cloned() {
+                    Some(Token::RParen) => {
+                        self.bump();
+                        break;
+                    }
+                    Some(token) => match token.literal() {
+                        Some(text) => {
+                            value.push_str(&text);
+                            self.bump();
+                        }
+                        None => {
+                            let context = format!("{} value", name);
+                            self.report(Severity::Error, span, invalid_character(&token, &context));
+                            self.recover_to_close();
+                            return None;
+                        }
+                    },
+                    None => {
+                        self.report_unclosed();
+                        return None;
+                    }
+                }
+            }
+            let value = value.trim().to_string();
+            if value.is_empty() {
+                self.report(Severity::Error, start, format!("empty {} value", name));
+                return None;
+            }
+            return Some(value);
+        }
+
+        // tag_value := TAG_NAME ')'
+        fn parse_tag_value(self: &mut Self, name: &str) -> Option<String> {
+            self.skip_space();
+            let context = format!("{} value", name);
+            let tag = self.parse_tag_name(&context);
+            if tag.is_none() {
+                self.recover_to_close();
+                return None;
+            }
+            self.skip_space();
+            let span = self.span();
+            match self.peek().cloned() {
+                Some(Token::RParen) => {
+                    self.bump();
+                    return tag;
+                }
+                Some(Token::Ident(_)) => {
+                    let message = format!("unexpected whitespace in {}", context);
+                    self.report(Severity::Error, span, message);
+                    self.recover_to_close();
+                }
+                Some(token) => {
+                    self.report(Severity::Error, span, invalid_character(&token, &context));
+                    self.recover_to_close();
+                }
+                None => self.report_unclosed(),
+            }
+            return None;
+        }
+
+        fn parse_tag_name(self: &mut Self, context: &str) -> Option<String> {
+            let mut tag = String::new();
+            while let Some(text) = self.peek().and_then(|t| t.tag_literal()) {
+                tag.push_str(&text);
+                self.bump();
+            }
+            if tag.is_empty() {
+                let span = self.span();
+                let message = match self.peek().cloned() {
+                    Some(Token::RBracket) | Some(Token::RParen) | Some(Token::Comma) | None => {
+                        format!("expected a tag in {}", context)
+                    }
+                    Some(token) => invalid_character(&token, context),
+                };
+                self.report(Severity::Error, span, message);
+                return None;
+            }
+            return Some(tag);
+        }
+
+        // a raw value that must convert, reported as "{name} must be {expected}" otherwise
+        fn parse_converted<T>(
+            self: &mut Self,
+            name: &str,
+            expected: &str,
+            convert: impl Fn(&str) -> Option<T>,
+        ) -> Option<T> {
+            let start = self.span().start;
+            let value = self.parse_raw()?;
+            if let Some(converted) = convert(&value) {
+                return Some(converted);
+            }
+            let end = self.tokens[self.pos - 1].span.start;
+            self.report(
+                Severity::Error,
+                start..end,
+                format!("{} must be {}, found '{}'", name, expected, value),
+            );
+            return None;
+        }
+
+        fn parse_raw(self: &mut Self) -> Option<String> {
+            let mut value = String::new();
+            let mut depth = 0;
+            while let Some(t) = self.bump() {
+                match t.token {
+                    Token::LParen => depth += 1,
+                    Token::RParen if depth == 0 => return Some(value.trim().to_string()),
+                    Token::RParen => depth -= 1,
+                    _ => {}
+                }
+                value.push_str(&t.token.source());
+            }
+            self.report_unclosed();
+            return None;
+        }
+
+        // body := (slot | segment | text)* ')'
+        fn parse_template_body(self: &mut Self) -> Option<Vec<TemplateElement>> {
+            let start = self.span();
+            let elements = self.parse_template_elements(&mut Vec::new(), None)?;
+            if elements.is_empty() {
+                self.report(Severity::Error, start, "empty TEMPLATE".to_string());
+                return None;
+            }
+            return Some(elements);
+        }
+
+        // elements up to the ')' closing the TEMPLATE, or the ']' closing the optional
+        // segment opened at `segment`
+        fn parse_template_elements(
+            self: &mut Self,
+            bound: &mut Vec<String>,
+            segment: Option<Span>,
+        ) -> Option<Vec<TemplateElement>> {
+            let mut elements: Vec<TemplateElement> = Vec::new();
+            let mut text = String::new();
+            loop {
+                let span = self.span();
+                match self.peek().cloned() {
+                    None => {
+                        self.report_unclosed();
+                        return None;
+                    }
+                    Some(Token::RParen) => {
+                        self.bump();
+                        if let Some(open) = segment {
+                            let message = "unclosed optional segment".to_string();
+                            self.report(Severity::Error, open, message);
+                            return None;
+                        }
+                        break;
+                    }
+                    Some(Token::RBracket) if segment.is_some() => {
+                        self.bump();
+                        break;
+                    }
+                    Some(Token::LBracket) => {
+                        if !text.is_empty() {
+                            elements.push(TemplateElement::Text(text.clone()));
+                            text.clear();
+                        }
+                        self.bump();
+                        // bindings made inside a segment are only visible inside it
+                        let mut scoped = bound.clone();
+                        let inner =
+                            self.parse_template_elements(&mut scoped, Some(span.clone()))?;
+                        if inner.is_empty() {
+                            let message = "empty optional segment".to_string();
+                            self.report(Severity::Error, span, message);
+                            self.recover_to_close();
+                            return None;
+                        }
+                        let chance = self.parse_chance()?;
+                        elements.push(TemplateElement::Optional(inner, chance));
+                    }
+                    Some(Token::Ident(keyword))
+                        if keyword.eq(NAME_WRAPPER)
+                            && matches!(self.peek_at(1), Some(Token::LBracket)) =>
+                    {
+                        if !text.is_empty() {
+                            elements.push(TemplateElement::Text(text.clone()));
+                            text.clear();
+                        }
+                        match self.parse_name_slot() {
+                            Some(name) => elements.push(TemplateElement::Named(name)),
+                            None => {
+                                self.recover_to_close();
+                                return None;
+                            }
+                        }
+                    }
+                    Some(Token::Ident(keyword))
+                        if (keyword.eq(NOUN_WRAPPER)
+                            || keyword.eq(ADJECTIVE_WRAPPER)
+                            || keyword.eq(TEMPLATE_WRAPPER))
+                            && matches!(self.peek_at(1), Some(Token::LBracket)) =>
+                    {
+                        if !text.is_empty() {
+                            elements.push(TemplateElement::Text(text.clone()));
+                            text.clear();
+                        }
+                        match self.parse_slot() {
+                            Some(Query {
+                                word_type: None,
+                                expr,
+                            }) => elements.push(TemplateElement::Nested(expr)),
+                            Some(pattern) => match self.parse_variable() {
+                                Some((name, span)) if bound.contains(&name) => {
+                                    let message = format!("@{} is already bound", name);
+                                    self.report(Severity::Error, span, message);
+                                    self.recover_to_close();
+                                    return None;
+                                }
+                                Some((name, _)) => {
+                                    bound.push(name.clone());
+                                    elements.push(TemplateElement::Bound(pattern, name));
+                                }
+                                None => elements.push(TemplateElement::Slot(pattern)),
+                            },
+                            None => {
+                                self.recover_to_close();
+                                return None;
+                            }
+                        }
+                    }
+                    Some(Token::Symbol('@'))
+                        if matches!(self.peek_at(1), Some(Token::Ident(_))) =>
+                    {
+                        let (name, span) = self.parse_variable()?;
+                        // most likely an address or a handle, so it is kept as written
+                        if !bound.contains(&name) {
+                            let message = format!(
+                                "@{} is used before it is bound, so it is kept as text",
+                                name
+                            );
+                            self.report(Severity::Warning, span, message);
+                            text.push_str(&format!("@{}", name));
+                            continue;
+                        }
+                        if !text.is_empty() {
+                            elements.push(TemplateElement::Text(text.clone()));
+                            text.clear();
+                        }
+                        match self.parse_relation() {
+                            Some(relation) => {
+                                elements.push(TemplateElement::Related(name, relation))
+                            }
+                            None => elements.push(TemplateElement::Variable(name)),
+                        }
+                    }
+                    Some(Token::RBracket) => {
+                        self.report(
+                            Severity::Error,
+                            span,
+                            "unbalanced brackets in TEMPLATE slot".to_string(),
+                        );
+                        self.recover_to_close();
+                        return None;
+                    }
+                    Some(token) => match token.literal() {
+                        Some(literal) => {
+                            text.push_str(&literal);
+                            self.bump();
+                        }
+                        None => {
+                            self.report(
+                                Severity::Error,
+                                span,
+                                invalid_character(&token, TEMPLATE_WRAPPER),
+                            );
+                            self.recover_to_close();
+                            return None;
+                        }
+                    },
+                }
+            }
+            if !text.is_empty() {
+                elements.push(TemplateElement::Text(text));
+            }
+            return Some(elements);
+        }
+
+        // '?' after an optional segment, then the percent chance it is rendered, 50% when
+        // that is left off
+        fn parse_chance(self: &mut Self) -> Option<u8> {
+            let span = self.span();
+            if !matches!(self.peek(), Some(Token::Symbol('?'))) {
+                let message = "expected '?' after optional segment".to_string();
+                self.report(Severity::Error, span, message);
+                self.recover_to_close();
+                return None;
+            }
+            self.bump();
+            if let (Some(Token::Ident(value)), Some(Token::Symbol('%'))) =
+                (self.peek().cloned(), self.peek_at(1))
+            {
+                let start = self.span().start;
+                self.bump();
+                let end = self.span().end;
+                self.bump();
+                match value.parse::<u8>() {
+                    Ok(chance) if chance <= 100 => return Some(chance),
+                    _ => {
+                        let message = format!(
+                            "optional segment chance must be a whole number from 0 to 100, found '{}%'",
+                            value
+                        );
+                        self.report(Severity::Error, start..end, message);
+                        self.recover_to_close();
+                        return None;
+                    }
+                }
+            }
+            // anything else written straight after the '?' is a chance gone wrong, such as
+            // '?x' or '?50 %', rather than text to keep
+            if matches!(
+                self.peek(),
+                Some(Token::Ident(_)) | Some(Token::Symbol('%'))
+            ) {
+                let start = self.span().start;
+                let mut found = String::new();
+                while let Some(token) = self.peek() {
+                    let spaced_percent = matches!(token, Token::Space(_))
+                        && matches!(self.peek_at(1), Some(Token::Symbol('%')));
+                    if !matches!(token, Token::Ident(_) | Token::Symbol(_)) && !spaced_percent {
+                        break;
+                    }
+                    found.push_str(&token.source());
+                    self.bump();
+                }
+                let end = self.tokens[self.pos - 1].span.end;
+                let message = format!(
+                    "expected an optional segment chance like '25%' after '?', found '{}'",
+                    found
+                );
+                self.report(Severity::Error, start..end, message);
+                self.recover_to_close();
+                return None;
+            }
+            return Some(50);
+        }
+
+        // '@' and a name, binding the slot before it or using that binding. A '@' with no
+        // name after it is plain text, as is '\@'.
+        fn parse_variable(self: &mut Self) -> Option<(String, Span)> {
+            let start = self.span().start;
+            if let (Some(Token::Symbol('@')), Some(Token::Ident(name))) =
+                (self.peek(), self.peek_at(1).cloned())
+            {
+                self.bump();
+                let end = self.span().end;
+                self.bump();
+                return Some((name, start..end));
+            }
+            return None;
+        }
+
+        // '.NOUN' or '.ADJECTIVE' straight after a variable, anything else is left as text
+        fn parse_relation(self: &mut Self) -> Option<WordRelationType> {
+            if let (Some(Token::Symbol('.')), Some(Token::Ident(keyword))) =
+                (self.peek(), self.peek_at(1))
+            {
+                let relation = WordRelationType::from_keyword(keyword)?;
+                self.bump();
+                self.bump();
+                return Some(relation);
+            }
+            return None;
+        }
+
+        // slot := ('NOUN' | 'ADJECTIVE') '[' (group+ | expr) ']'
+        // group := '[' TAG_NAME (',' TAG_NAME)* ']'
+        // a TEMPLATE slot comes back without a word type
+        fn parse_slot(self: &mut Self) -> Option<Query> {
+            let word_type = match self.bump().map(|t| t.token) {
+                Some(Token::Ident(keyword)) if keyword.eq(ADJECTIVE_WRAPPER) => {
+                    Some(WordType::Adjective)
+                }
+                Some(Token::Ident(keyword)) if keyword.eq(TEMPLATE_WRAPPER) => None,
+                _ => Some(WordType::Noun),
+            };
+            let open = self.span();
+            self.bump();
+            let unbalanced = |parser: &mut Self| {
+                parser.report(
+                    Severity::Error,
+                    open.clone(),
+                    "unbalanced brackets in TEMPLATE slot".to_string(),
+                );
+            };
+            self.skip_space();
+            if !matches!(
+                self.peek(),
+                Some(Token::LBracket) | Some(Token::RBracket) | None
+            ) {
+                let expr = self.parse_query_expr()?;
+                self.skip_space();
+                let span = self.span();
+                match self.peek() {
+                    Some(Token::RBracket) => {
+                        self.bump();
+                        return Some(Query { word_type, expr });
+                    }
+                    Some(_) => self.report(
+                        Severity::Error,
+                        span,
+                        "expected '&', '|' or ']' in TEMPLATE slot".to_string(),
+                    ),
+                    None => unbalanced(self),
+                }
+                return None;
+            }
+            let mut groups: Vec<Vec<String>> = Vec::new();
+            loop {
+                self.skip_space();
+                match self.peek() {
+                    Some(Token::LBracket) => {
+                        self.bump();
+                        groups.push(self.parse_group()?);
+                    }
+                    Some(Token::RBracket) if !groups.is_empty() => {
+                        self.bump();
+                        return Some(Query {
+                            word_type,
+                            expr: QueryExpr::from_groups(groups),
+                        });
+                    }
+                    Some(Token::RBracket) => {
+                        let span = self.span();
+                        self.report(Severity::Error, span, "empty TEMPLATE slot".to_string());
+                        return None;
+                    }
+                    _ => {
+                        unbalanced(self);
+                        return None;
+                    }
+                }
+            }
+        }
+
+        // NAME[TavernFull], one template referred to by its NAME
+        fn parse_name_slot(self: &mut Self) -> Option<String> {
+            self.bump();
+            let open = self.span();
+            self.bump();
+            self.skip_space();
+            let name = self.parse_tag_name("TEMPLATE slot")?;
+            self.skip_space();
+            let span = self.span();
+            match self.peek() {
+                Some(Token::RBracket) => {
+                    self.bump();
+                    return Some(name);
+                }
+                Some(_) => self.report(
+                    Severity::Error,
+                    span,
+                    "expected ']' after the NAME in TEMPLATE slot".to_string(),
+                ),
+                None => self.report(
+                    Severity::Error,
+                    open,
+                    "unbalanced brackets in TEMPLATE slot".to_string(),
+                ),
+            }
+            return None;
+        }
+
+        fn parse_group(self: &mut Self) -> Option<Vec<String>> {
+            let mut tags: Vec<String> = Vec::new();
+            loop {
+                self.skip_space();
+                tags.push(self.parse_tag_name("TEMPLATE slot")?);
+                self.skip_space();
+                let span = self.span();
+                match self.bump().map(|t| t.token) {
+                    Some(Token::Comma) => continue,
+                    Some(Token::RBracket) => return Some(tags),
+                    _ => {
+                        self.report(
+                            Severity::Error,
+                            span,
+                            "unbalanced brackets in TEMPLATE slot".to_string(),
+                        );
+                        return None;
+                    }
+                }
+            }
+        }
+
+        // expr := and ('|' and)*
+        fn parse_query_expr(self: &mut Self) -> Option<QueryExpr> {
+            let mut terms: Vec<QueryExpr> = vec![self.parse_query_and()?];
+            loop {
+                self.skip_space();
+                if !matches!(self.peek(), Some(Token::Symbol('|'))) {
+                    break;
+                }
+                self.bump();
+                terms.push(self.parse_query_and()?);
+            }
+            if terms.len() == 1 {
+                return terms.pop();
+            }
+            return Some(QueryExpr::Or(terms));
+        }
+
+        // and := unary ('&' unary)*
+        fn parse_query_and(self: &mut Self) -> Option<QueryExpr> {
+            let mut terms: Vec<QueryExpr> = vec![self.parse_query_unary()?];
+            loop {
+                self.skip_space();
+                if !matches!(self.peek(), Some(Token::Symbol('&'))) {
+                    break;
+                }
+                self.bump();
+                terms.push(self.parse_query_unary()?);
+            }
+            if terms.len() == 1 {
+                return terms.pop();
+            }
+            return Some(QueryExpr::And(terms));
+        }
+
+        // unary := '!' unary | '(' expr ')' | TAG_NAME
+        fn parse_query_unary(self: &mut Self) -> Option<QueryExpr> {
+            self.skip_space();
+            match self.peek() {
+                Some(Token::Symbol('!')) => {
+                    self.bump();
+                    return self
+                        .parse_query_unary()
+                        .map(|expr| QueryExpr::Not(Box::new(expr)));
+                }
+                Some(Token::LParen) => {
+                    let open = self.span();
+                    self.bump();
+                    let expr = self.parse_query_expr()?;
+                    self.skip_space();
+                    if matches!(self.peek(), Some(Token::RParen)) {
+                        self.bump();
+                        return Some(expr);
+                    }
+                    self.report(Severity::Error, open, "unclosed '(' in query".to_string());
+                    return None;
+                }
+                _ => return self.parse_tag_name("query").map(QueryExpr::Tag),
+            }
+        }
+
+        fn check_entries(self: &mut Self, entries: &[(Option<Entry>, Span, String)]) {
+            let mut seen: Vec<&str> = Vec::new();
+            for (_, span, name) in entries {
+                if name.is_empty() {
+                    continue;
+                }
+                if seen.contains(&name.as_str())
+                    && !name.eq(TAG_WRAPPER)
+                    && !name.eq(TAG_PARENT_WRAPPER)
+                    && !name.eq(INCLUDE_WRAPPER)
+                {
+                    self.report(
+                        Severity::Warning,
+                        span.clone(),
+                        format!("duplicate {}, only the first is used", name),
+                    );
+                }
+                seen.push(name);
+            }
+            let line_span = 0..self.tokens.last().map(|t| t.span.end).unwrap_or(0);
+            let has = |wrapper: &str| seen.contains(&wrapper);
+            if has(TAG_WRAPPER)
+                && !(has(NOUN_WRAPPER)
+                    || has(ADJECTIVE_WRAPPER)
+                    || has(TEMPLATE_WRAPPER)
+                    || has(TAG_PARENT_WRAPPER))
+            {
+                self.report(
+                    Severity::Warning,
+                    line_span.clone(),
+                    "TAG has no NOUN, ADJECTIVE, TEMPLATE or HAS_PARENT to apply to".to_string(),
+                );
+            }
+            if has(TAG_PARENT_WRAPPER) && !has(TAG_WRAPPER) {
+                self.report(
+                    Severity::Warning,
+                    line_span.clone(),
+                    "HAS_PARENT without a TAG".to_string(),
+                );
+            }
+            if has(WEIGHT_WRAPPER)
+                && !(has(NOUN_WRAPPER) || has(ADJECTIVE_WRAPPER) || has(TEMPLATE_WRAPPER))
+            {
+                self.report(
+                    Severity::Warning,
+                    line_span.clone(),
+                    "WEIGHT has no NOUN, ADJECTIVE or TEMPLATE to apply to".to_string(),
+                );
+            }
+            if has(NAME_WRAPPER) && !has(TEMPLATE_WRAPPER) {
+                self.report(
+                    Severity::Warning,
+                    line_span.clone(),
+                    "NAME has no TEMPLATE to apply to".to_string(),
+                );
+            }
+            for wrapper in [RARITY_WRAPPER, RANK_WRAPPER] {
+                if has(wrapper) && !(has(NOUN_WRAPPER) || has(ADJECTIVE_WRAPPER)) {
+                    self.report(
+                        Severity::Warning,
+                        line_span.clone(),
+                        format!("{} has no NOUN or ADJECTIVE to apply to", wrapper),
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let tokens: Vec<Token> = tokenize("NOUN(Bull's), TAG(A_b)")
+            .into_iter()
+            .map(|t| t.token)
+            .collect();
+        assert!(tokens.eq(&vec![
+            Token::Ident("NOUN".to_string()),
+            Token::LParen,
+            Token::Ident("Bull".to_string()),
+            Token::Symbol('\''),
+            Token::Ident("s".to_string()),
+            Token::RParen,
+            Token::Comma,
+            Token::Space(" ".to_string()),
+            Token::Ident("TAG".to_string()),
+            Token::LParen,
+            Token::Ident("A_b".to_string()),
+            Token::RParen,
+        ]));
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let parsed = parse_line(
+            "test",
+            1,
+            "TEMPLATE(ADJECTIVE[[Large, Medium][Mammal]] Inn), NOUN(Extremely Long), TAG(Size)",
+        );
+        assert!(parsed.diagnostics.is_empty());
+        assert!(parsed.entries.len().eq(&3));
+        assert!(parsed.entries[0].0.eq(&Entry::Template(vec![
+            TemplateElement::Slot(Query::from((
+                WordType::Adjective,
+                vec![
+                    vec!["Large".to_string(), "Medium".to_string()],
+                    vec!["Mammal".to_string()]
+                ]
+            ))),
+            TemplateElement::Text(" Inn".to_string()),
+        ])));
+        assert!(parsed.entries[1]
+            .0
+            .eq(&Entry::Noun("Extremely Long".to_string())));
+        assert_eq!(parsed.entries[2].1, 72..81);
+        assert!(parse_line("test", 1, "// Metals").entries.is_empty());
+    }
+
+    #[test]
+    fn test_parse_diagnostics() {
+        let unknown = parse_line("test", 3, "NOUN(Steel), FOO(Bar)").diagnostics;
+        assert!(unknown.len().eq(&1));
+        assert!(unknown[0].severity.eq(&Severity::Error));
+        assert_eq!(unknown[0].span, 13..16);
+        assert!(unknown[0].message.eq("unknown wrapper FOO"));
+        assert!(unknown[0]
+            .to_string()
+            .eq("test:3:14: error: unknown wrapper FOO"));
+
+        let brackets = parse_line("test", 1, "TEMPLATE(NOUN[[Metal] Pub)").diagnostics;
+        assert!(brackets.len().eq(&1));
+        assert!(brackets[0]
+            .message
+            .eq("unbalanced brackets in TEMPLATE slot"));
+        assert_eq!(brackets[0].span, 13..14);
+
+        let invalid = parse_line("test", 1, "NOUN(St[eel)").diagnostics;
+        assert_eq!(invalid[0].span, 7..8);
+
+        let unclosed = parse_line("test", 1, "NOUN(Steel, TAG(Metal)").diagnostics;
+        assert!(unclosed[0]
+            .message
+            .eq("invalid character ',' in NOUN value, escape it as '\\,'"));
+
+        let stray = parse_line("test", 1, "Aluminium, TAG(Metal)").diagnostics;
+        assert!(stray.len().eq(&2));
+        assert!(stray.iter().all(|d| d.severity.eq(&Severity::Warning)));
+        assert!(stray[0].message.eq("unexpected text 'Aluminium'"));
+    }
+
+    #[test]
+    fn test_parse_weight() {
+        let parsed = parse_line("test", 1, "NOUN(Steel), WEIGHT(2.5)");
+        assert!(parsed.diagnostics.is_empty());
+        assert!(parsed
+            .entries
+            .iter()
+            .any(|(entry, _)| entry.eq(&Entry::Weight(2.5))));
+        assert!(parsed.key.eq(&parse_line("test", 1, "NOUN(Steel)").key));
+
+        for (line, value) in [
+            ("NOUN(Steel), WEIGHT(-1)", "-1"),
+            ("NOUN(Steel), WEIGHT(abc)", "abc"),
+        ] {
+            let diagnostics = parse_line("test", 1, line).diagnostics;
+            assert!(diagnostics.len().eq(&1));
+            assert!(diagnostics[0].severity.eq(&Severity::Error));
+            assert!(diagnostics[0].message.eq(&format!(
+                "WEIGHT must be a positive number, found '{}'",
+                value
+            )));
+        }
+
+        let orphan = parse_line("test", 1, "TAG(Metal), HAS_PARENT(Thing), WEIGHT(2)").diagnostics;
+        assert!(orphan.len().eq(&1));
+        assert!(orphan[0]
+            .message
+            .eq("WEIGHT has no NOUN, ADJECTIVE or TEMPLATE to apply to"));
+    }
+
+    #[test]
+    fn test_parse_rarity_and_rank() {
+        let parsed = parse_line("test", 1, "NOUN(Steel), RARITY(Rare), RANK(3)");
+        assert!(parsed.diagnostics.is_empty());
+        assert!(parsed.entries[1].0.eq(&Entry::Rarity(Rarity::Rare)));
+        assert!(parsed.entries[2].0.eq(&Entry::Rank(3)));
+        assert!(parsed.key.eq(&parse_line("test", 1, "NOUN(Steel)").key));
+
+        let tier = parse_line("test", 1, "NOUN(Steel), RARITY(Mythic)").diagnostics;
+        assert!(tier[0]
+            .message
+            .eq("RARITY must be one of Common, Uncommon, Rare or Legendary, found 'Mythic'"));
+        let rank = parse_line("test", 1, "NOUN(Steel), RANK(0)").diagnostics;
+        assert!(rank[0]
+            .message
+            .eq("RANK must be a whole number from 1, found '0'"));
+        let orphan = parse_line("test", 1, "TEMPLATE(Inn), RANK(1)").diagnostics;
+        assert!(orphan[0]
+            .message
+            .eq("RANK has no NOUN or ADJECTIVE to apply to"));
+    }
+
+    #[test]
+    fn test_parse_variables() {
+        use crate::dictionary::template::template::parse_template;
+        let template =
+            parse_template("TEMPLATE(The NOUN[Metal]@m Bull — finest @m in town)").unwrap();
+        assert!(template.template.eq(&vec![
+            TemplateElement::Text("The ".to_string()),
+            TemplateElement::Bound("NOUN[Metal]".parse().unwrap(), "m".to_string()),
+            TemplateElement::Text(" Bull — finest ".to_string()),
+            TemplateElement::Variable("m".to_string()),
+            TemplateElement::Text(" in town".to_string()),
+        ]));
+
+        let related = parse_template("TEMPLATE(NOUN[Metal]@m: @m.ADJECTIVE @m.Etc)").unwrap();
+        assert!(related.template[2].eq(&TemplateElement::Related(
+            "m".to_string(),
+            WordRelationType::Adjective
+        )));
+        assert!(related.template[4].eq(&TemplateElement::Variable("m".to_string())));
+        assert!(related.template[5].eq(&TemplateElement::Text(".Etc".to_string())));
+
+        let literal = parse_template("TEMPLATE(NOUN[Metal] @ inn\\@home)").unwrap();
+        assert!(literal.template[1].eq(&TemplateElement::Text(" @ inn@home".to_string())));
+
+        let unbound = parse_line("test", 1, "TEMPLATE(@m NOUN[Metal]@m)");
+        let warning = &unbound.diagnostics[0];
+        assert!(warning
+            .message
+            .eq("@m is used before it is bound, so it is kept as text"));
+        assert!(warning.severity.eq(&Severity::Warning));
+        assert_eq!(warning.span, 9..11);
+        let text = parse_template("TEMPLATE(Mail @bob.NOUN or NOUN[Metal]@m)").unwrap();
+        assert!(text.template[0].eq(&TemplateElement::Text("Mail @bob.NOUN or ".to_string())));
+        assert!(escape("inn@home").eq("inn\\@home"));
+        let twice = parse_line("test", 1, "TEMPLATE(NOUN[Metal]@m NOUN[Wood]@m)").diagnostics;
+        assert!(twice[0].message.eq("@m is already bound"));
+        assert_eq!(twice[0].span, 33..35);
+    }
+
+    #[test]
+    fn fuzz_parse_line() {
+        use proptest::test_runner::{Config, TestRunner};
+        let mut runner = TestRunner::new(Config {
+            failure_persistence: None,
+            ..Config::default()
+        });
+        let check = |line: String| {
+            let parsed = parse_line("fuzz", 1, &line);
+            let length = line.chars().count();
+            for d in &parsed.diagnostics {
+                assert!(d.span.start <= d.span.end && d.span.end <= length);
+            }
+            Ok(())
+        };
+        runner.run(&".{0,60}", check).unwrap();
+        runner
+            .run(
+                &"(NOUN|ADJECTIVE|TEMPLATE|TAG|HAS_PARENT|ID|[a-z]|[()\\[\\],' ]){0,40}",
+                check,
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn fuzz_word_round_trip() {
+        use crate::dictionary::word::word::parse_word;
+        use proptest::{
+            collection::vec,
+            option,
+            test_runner::{Config, TestRunner},
+        };
+        let mut runner = TestRunner::new(Config {
+            failure_persistence: None,
+            ..Config::default()
+        });
+        let strategy = (
+            "[^\\s]([^\\n]{0,10}[^\\s])?",
+            option::of("[^\\s]{1,8}"),
+            vec("[\\p{L}\\p{N}_\\-'.]{1,8}", 0..4),
+        );
+        runner
+            .run(&strategy, |(noun, adjective, tags)| {
+                let mut line = format!("{}({})", NOUN_WRAPPER, escape(&noun));
+                if let Some(adjective) = &adjective {
+                    line.push_str(&format!(", {}({})", ADJECTIVE_WRAPPER, escape(adjective)));
+                }
+                for tag in &tags {
+                    line.push_str(&format!(",{}({})", TAG_WRAPPER, tag));
+                }
+                assert!(parse_line("fuzz", 1, &line).diagnostics.is_empty());
+                let words = parse_word(&line);
+                assert!(words.len().eq(&(1 + adjective.iter().len())));
+                assert!(words[0].base.eq(&noun));
+                assert!(words.iter().all(|w| w
+                    .tags
+                    .len()
+                    .eq(&tags.iter().collect::<std::collections::BTreeSet<_>>().len())));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn fuzz_template_round_trip() {
+        use crate::dictionary::template::template::parse_template;
+        use proptest::{
+            collection::vec,
+            prelude::*,
+            test_runner::{Config, TestRunner},
+        };
+
+        // drops uses of names not bound in their scope, turns rebindings into plain slots,
+        // drops empty segments, merges adjacent text and spaces names from slots and
+        // keywords, leaving exactly what parsing the printed form gives back
+        fn settle(elements: Vec<TemplateElement>, bound: &mut Vec<String>) -> Vec<TemplateElement> {
+            let mut output: Vec<TemplateElement> = Vec::new();
+            for element in elements {
+                let element = match element {
+                    TemplateElement::Bound(query, name) if bound.contains(&name) => {
+                        TemplateElement::Slot(query)
+                    }
+                    TemplateElement::Bound(query, name) => {
+                        bound.push(name.clone());
+                        TemplateElement::Bound(query, name)
+                    }
+                    TemplateElement::Variable(name) | TemplateElement::Related(name, _)
+                        if !bound.contains(&name) =>
+                    {
+                        continue;
+                    }
+                    TemplateElement::Optional(inner, chance) => {
+                        let inner = settle(inner, &mut bound.clone());
+                        if inner.is_empty() {
+                            continue;
+                        }
+                        TemplateElement::Optional(inner, chance)
+                    }
+                    TemplateElement::Text(text) => {
+                        if let Some(TemplateElement::Text(last)) = output.last_mut() {
+                            last.push_str(&text);
+                            continue;
+                        }
+                        TemplateElement::Text(text)
+                    }
+                    element => element,
+                };
+                // a name runs straight into a keyword after it, and a use of a name straight
+                // after a slot binds it, unless they are spaced
+                let binds = matches!(output.last(), Some(TemplateElement::Slot(_)))
+                    && matches!(
+                        element,
+                        TemplateElement::Variable(_) | TemplateElement::Related(..)
+                    );
+                let ends_in_name = matches!(
+                    output.last(),
+                    Some(TemplateElement::Bound(..))
+                        | Some(TemplateElement::Variable(_))
+                        | Some(TemplateElement::Related(..))
+                );
+                let starts_with_keyword = matches!(
+                    element,
+                    TemplateElement::Slot(_)
+                        | TemplateElement::Bound(..)
+                        | TemplateElement::Nested(_)
+                        | TemplateElement::Named(_)
+                );
+                if binds || (ends_in_name && starts_with_keyword) {
+                    output.push(TemplateElement::Text(" ".to_string()));
+                }
+                output.push(element);
+            }
+            return output;
+        }
+
+        fn print(elements: &[TemplateElement]) -> String {
+            return elements
+                .iter()
+                .map(|element| match element {
+                    TemplateElement::Text(text) => text.clone(),
+                    TemplateElement::Slot(query) => query.to_string(),
+                    TemplateElement::Nested(expr) => format!("{}[{}]", TEMPLATE_WRAPPER, expr),
+                    TemplateElement::Named(name) => format!("{}[{}]", NAME_WRAPPER, name),
+                    TemplateElement::Bound(query, name) => format!("{}@{}", query, name),
+                    TemplateElement::Variable(name) => format!("@{}", name),
+                    TemplateElement::Related(name, relation) => {
+                        format!("@{}.{}", name, relation.keyword())
+                    }
+                    TemplateElement::Optional(inner, chance) => {
+                        format!("[{}]?{}%", print(inner), chance)
+                    }
+                })
+                .collect();
+        }
+
+        let query = (
+            prop_oneof![Just(WordType::Noun), Just(WordType::Adjective)],
+            vec(vec("[A-Za-z0-9]{1,6}", 1..3), 1..3),
+        )
+            .prop_map(Query::from);
+        let relation = prop_oneof![
+            Just(WordRelationType::BaseNoun),
+            Just(WordRelationType::Adjective)
+        ];
+        // text starts and ends with a space or quote, so it never runs into a name or
+        // a keyword, and never holds '.' or '@'
+        let leaf = prop_oneof![
+            "[' ]([A-Za-z' ]{0,8}[' ])?".prop_map(TemplateElement::Text),
+            query.clone().prop_map(TemplateElement::Slot),
+            vec(vec("[A-Za-z0-9]{1,6}", 1..3), 1..3)
+                .prop_map(|groups| TemplateElement::Nested(QueryExpr::from_groups(groups))),
+            "[A-Za-z0-9]{1,6}".prop_map(TemplateElement::Named),
+            (query, "[a-c]").prop_map(|(query, name)| TemplateElement::Bound(query, name)),
+            "[a-c]".prop_map(TemplateElement::Variable),
+            ("[a-c]", relation)
+                .prop_map(|(name, relation)| TemplateElement::Related(name, relation)),
+        ];
+        let element = leaf.prop_recursive(3, 24, 4, |inner| {
+            (vec(inner, 1..4), 0..=100u8)
+                .prop_map(|(segment, chance)| TemplateElement::Optional(segment, chance))
+        });
+        let mut runner = TestRunner::new(Config {
+            failure_persistence: None,
+            ..Config::default()
+        });
+        runner
+            .run(&vec(element, 1..6), |elements| {
+                let expected = settle(elements, &mut Vec::new());
+                if expected.is_empty() {
+                    return Ok(());
+                }
+                let line = format!("{}({}), TAG(Fuzz)", TEMPLATE_WRAPPER, print(&expected));
+                assert!(parse_line("fuzz", 1, &line).diagnostics.is_empty());
+                let template = parse_template(&line).unwrap();
+                assert!(template.template.eq(&expected));
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_parse_optional_segments() {
+        use crate::dictionary::template::template::parse_template;
+        let template =
+            parse_template("TEMPLATE(The [ADJECTIVE[[Colour]] ]?50%Bull[ [Inn]?]?25%)").unwrap();
+        assert!(template.template.eq(&vec![
+            TemplateElement::Text("The ".to_string()),
+            TemplateElement::Optional(
+                vec![
+                    TemplateElement::Slot("ADJECTIVE[Colour]".parse().unwrap()),
+                    TemplateElement::Text(" ".to_string()),
+                ],
+                50
+            ),
+            TemplateElement::Text("Bull".to_string()),
+            TemplateElement::Optional(
+                vec![
+                    TemplateElement::Text(" ".to_string()),
+                    TemplateElement::Optional(vec![TemplateElement::Text("Inn".to_string())], 50),
+                ],
+                25
+            ),
+        ]));
+        let literal = parse_template("TEMPLATE([Old]?100% Bull? 50%)").unwrap();
+        assert!(literal.template[1].eq(&TemplateElement::Text(" Bull? 50%".to_string())));
+
+        for (line, message, span) in [
+            (
+                "TEMPLATE(The [Old] Bull)",
+                "expected '?' after optional segment",
+                18..19,
+            ),
+            (
+                "TEMPLATE([Old]?150% Bull)",
+                "optional segment chance must be a whole number from 0 to 100, found '150%'",
+                15..19,
+            ),
+            (
+                "TEMPLATE([Old]?50 % Bull)",
+                "expected an optional segment chance like '25%' after '?', found '50 %'",
+                15..19,
+            ),
+            (
+                "TEMPLATE([Old]?x Bull)",
+                "expected an optional segment chance like '25%' after '?', found 'x'",
+                15..16,
+            ),
+            (
+                "TEMPLATE([Old]?% Bull)",
+                "expected an optional segment chance like '25%' after '?', found '%'",
+                15..16,
+            ),
+            ("TEMPLATE(The []?50%)", "empty optional segment", 13..14),
+            (
+                "TEMPLATE(The [Old Bull)",
+                "unclosed optional segment",
+                13..14,
+            ),
+            (
+                "TEMPLATE([NOUN[Metal]@m ]?50%@m)",
+                "@m is used before it is bound, so it is kept as text",
+                29..31,
+            ),
+        ] {
+            let diagnostics = parse_line("test", 1, line).diagnostics;
+            assert!(diagnostics[0].message.eq(message));
+            assert_eq!(diagnostics[0].span, span);
+        }
+        // out of its segment the name is only text
+        let outside = parse_template("TEMPLATE([NOUN[Metal]@m ]?50%@m)").unwrap();
+        assert!(outside.template[1].eq(&TemplateElement::Text("@m".to_string())));
+    }
+
+    #[test]
+    fn test_unicode_and_escapes() {
+        for (line, expected) in [
+            ("NOUN(Zoë), TAG(FirstName)", "Zoë"),
+            ("NOUN(Öztürk), TAG(LastName)", "Öztürk"),
+            ("NOUN(Saint-Malo), TAG(Town)", "Saint-Malo"),
+            ("NOUN(Jack & Jill), TAG(Pub)", "Jack & Jill"),
+            ("NOUN(No. 9), TAG(Street)", "No. 9"),
+            ("NOUN(The Crown \\(Old\\)), TAG(Pub)", "The Crown (Old)"),
+            (
+                "NOUN(Salt\\, Pepper \\[and\\] Sons), TAG(Shop)",
+                "Salt, Pepper [and] Sons",
+            ),
+        ] {
+            let parsed = parse_line("test", 1, line);
+            assert!(parsed.diagnostics.is_empty());
+            assert!(parsed.entries[0].0.eq(&Entry::Noun(expected.to_string())));
+            assert!(escape(expected).eq(&line[5..line.find("), TAG").unwrap()]));
+        }
+
+        let parsed = parse_line(
+            "test",
+            1,
+            "TEMPLATE(Café NOUN[[Straße, Saint-Malo]] No. 9 \\(est. 1850\\)), TAG(Café)",
+        );
+        assert!(parsed.diagnostics.is_empty());
+        assert!(parsed.entries[0].0.eq(&Entry::Template(vec![
+            TemplateElement::Text("Café ".to_string()),
+            TemplateElement::Slot(Query::from((
+                WordType::Noun,
+                vec![vec!["Straße".to_string(), "Saint-Malo".to_string()]]
+            ))),
+            TemplateElement::Text(" No. 9 (est. 1850)".to_string()),
+        ])));
+        assert!(parsed.entries[1].0.eq(&Entry::Tag("Café".to_string())));
+
+        let dangling = parse_line("test", 1, "NOUN(Steel\\").diagnostics;
+        assert!(dangling[0].message.eq("dangling '\\' in NOUN value"));
+        let spaced = parse_line("test", 1, "TAG(Two Words), HAS_PARENT(Tag)").diagnostics;
+        assert!(spaced[0].message.eq("unexpected whitespace in TAG value"));
+    }
+
+    #[test]
+    fn test_logical_lines() {
+        let lines = |lines: Vec<&str>| {
+            logical_lines(
+                lines
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, l)| (i + 1, l.to_string()))
+                    .collect(),
+            )
+        };
+        assert!(
+            lines(vec!["// comment", "  ", "# comment", "NOUN(Steel)\r"])
+                .eq(&vec![(4, "NOUN(Steel)".to_string())])
+        );
+        assert!(lines(vec!["TEMPLATE(The \\", "  Bull), \\  ", "TAG(Pub)"])
+            .eq(&vec![(1, "TEMPLATE(The Bull), TAG(Pub)".to_string())]));
+        assert!(lines(vec![
+            "NOUN(Back\\\\)",
+            "TAG(Pub) // not continued \\",
+            "NOUN(End) \\"
+        ])
+        .eq(&vec![
+            (1, "NOUN(Back\\\\)".to_string()),
+            (2, "TAG(Pub) ".to_string()),
+            (3, "NOUN(End) ".to_string())
+        ]));
+
+        let parsed = parse_line("test", 1, "NOUN(No\\# 9), TAG(Pub) # TAG(Ignored)");
+        assert!(parsed.diagnostics.is_empty());
+        assert!(parsed.entries.len().eq(&2));
+        assert!(parsed.entries[0].0.eq(&Entry::Noun("No# 9".to_string())));
+        assert!(parse_line("test", 1, "  // NOUN(Ignored)")
+            .entries
+            .is_empty());
+    }
+
+    #[test]
+    fn fuzz_query_round_trip() {
+        use proptest::{
+            collection::vec,
+            prelude::*,
+            test_runner::{Config, TestRunner},
+        };
+        let leaf = "[A-Za-z][A-Za-z0-9_\\-'.]{0,6}".prop_map(QueryExpr::Tag);
+        let expr = leaf.prop_recursive(4, 24, 3, |inner| {
+            prop_oneof![
+                vec(inner.clone(), 2..4).prop_map(QueryExpr::And),
+                vec(inner.clone(), 2..4).prop_map(QueryExpr::Or),
+                inner.prop_map(|e| QueryExpr::Not(Box::new(e))),
+            ]
+        });
+        let mut runner = TestRunner::new(Config {
+            failure_persistence: None,
+            ..Config::default()
+        });
+        runner
+            .run(&expr, |expr| {
+                let printed = Query {
+                    word_type: Some(WordType::Noun),
+                    expr,
+                }
+                .to_string();
+                let (query, diagnostics) = parse_query(&printed);
+                assert!(diagnostics.is_empty());
+                assert!(query.unwrap().to_string().eq(&printed));
+                Ok(())
+            })
+            .unwrap();
+    }
+}
@@ -0,0 +1,567 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
+pub mod query {
+    use std::{collections::BTreeSet, fmt, str::FromStr};
+
+    use rand::Rng;
+    use uuid::Uuid;
+
+    use crate::{
+        dictionary::{
+            diagnostic::diagnostic::Severity,
+            dictionary::{
+                Dictionary, SearchPattern, ADJECTIVE_WRAPPER, INLINE_SOURCE, NOUN_WRAPPER,
+            },
+            parser::parser::parse_query,
+            template::template::Template,
+            word::word::{Word, WordRelationType, WordType},
+        },
+        error::error::TemplaterError,
+    };
+
+    // a boolean combination of tags, written 'Metal & (Colour | Gem) & !Cursed' with
+    // '!' binding tightest, then '&', then '|'
+    #[derive(PartialEq, Debug, Clone, Eq, PartialOrd, Ord)]
+    pub enum QueryExpr {
+        Tag(String),
+        And(Vec<QueryExpr>),
+        Or(Vec<QueryExpr>),
+        Not(Box<QueryExpr>),
+    }
+
+    // a word type of None matches nouns and adjectives alike, templates ignore it
+    #[derive(PartialEq, Debug, Clone, Eq, PartialOrd, Ord)]
+    pub struct Query {
+        pub word_type: Option<WordType>,
+        pub expr: QueryExpr,
+    }
+
+    impl QueryExpr {
+        // the legacy [[a, b], [c]] groups: every group must match and any tag within a
+        // group may, so this reads as '(a | b) & c'. No groups at all matches nothing.
+        pub fn from_groups(groups: Vec<Vec<String>>) -> QueryExpr {
+            let mut terms: Vec<QueryExpr> = groups
+                .into_iter()
+                .map(|group| {
+                    let mut tags: Vec<QueryExpr> = group.into_iter().map(QueryExpr::Tag).collect();
+                    if tags.len() == 1 {
+                        return tags.pop().unwrap();
+                    }
+                    return QueryExpr::Or(tags);
+                })
+                .collect();
+            return match terms.len() {
+                0 => QueryExpr::Or(Vec::new()),
+                1 => terms.pop().unwrap(),
+                _ => QueryExpr::And(terms),
+            };
+        }
+    }
+
+    impl From<SearchPattern> for Query {
+        fn from(pattern: SearchPattern) -> Self {
+            let (word_type, groups) = pattern;
+            return Query {
+                word_type: Some(word_type),
+                expr: QueryExpr::from_groups(groups),
+            };
+        }
+    }
+
+    // legacy template tag groups, which carry no word type
+    impl From<Vec<Vec<String>>> for Query {
+        fn from(groups: Vec<Vec<String>>) -> Self {
+            return Query {
+                word_type: None,
+                expr: QueryExpr::from_groups(groups),
+            };
+        }
+    }
+
+    impl From<QueryExpr> for Query {
+        fn from(expr: QueryExpr) -> Self {
+            return Query {
+                word_type: None,
+                expr,
+            };
+        }
+    }
+
+    impl From<&Query> for Query {
+        fn from(query: &Query) -> Self {
+            return query.clone();
+        }
+    }
+
+    // builder, each call narrows the query further:
+    //  Query::noun().all_of(["HairStyle", "Personal"]).none_of(["Cursed"])
+    // a query with no terms yet matches nothing, the same as an empty legacy group list,
+    // so Query::noun() alone never draws from every noun
+    impl Query {
+        pub fn noun() -> Query {
+            return Query::of_type(Some(WordType::Noun));
+        }
+
+        pub fn adjective() -> Query {
+            return Query::of_type(Some(WordType::Adjective));
+        }
+
+        // any word type, or a template query
+        pub fn any() -> Query {
+            return Query::of_type(None);
+        }
+
+        fn of_type(word_type: Option<WordType>) -> Query {
+            return Query {
+                word_type,
+                expr: QueryExpr::Or(Vec::new()),
+            };
+        }
+
+        pub fn all_of<T: ToString>(self: Self, tags: impl IntoIterator<Item = T>) -> Query {
+            return tags.into_iter().fold(self, |query, tag| {
+                query.matching(QueryExpr::Tag(tag.to_string()))
+            });
+        }
+
+        pub fn any_of<T: ToString>(self: Self, tags: impl IntoIterator<Item = T>) -> Query {
+            return self.matching(QueryExpr::Or(tag_list(tags)));
+        }
+
+        pub fn none_of<T: ToString>(self: Self, tags: impl IntoIterator<Item = T>) -> Query {
+            return self.matching(QueryExpr::Not(Box::new(QueryExpr::Or(tag_list(tags)))));
+        }
+
+        // also require an arbitrary expression, e.g. one parsed from data
+        pub fn matching(mut self: Self, expr: QueryExpr) -> Query {
+            match &mut self.expr {
+                // the first term replaces the empty query
+                QueryExpr::Or(terms) if terms.is_empty() => self.expr = QueryExpr::And(vec![expr]),
+                QueryExpr::And(terms) => terms.push(expr),
+                other => {
+                    let previous = other.clone();
+                    self.expr = QueryExpr::And(vec![previous, expr]);
+                }
+            }
+            return self;
+        }
+    }
+
+    fn tag_list<T: ToString>(tags: impl IntoIterator<Item = T>) -> Vec<QueryExpr> {
+        return tags
+            .into_iter()
+            .map(|tag| QueryExpr::Tag(tag.to_string()))
+            .collect();
+    }
+
+    impl FromStr for Query {
+        type Err = TemplaterError;
+
+        fn from_str(text: &str) -> Result<Self, Self::Err> {
+            let (query, diagnostics) = parse_query(text);
+            let error = diagnostics
+                .into_iter()
+                .find(|d| d.severity.eq(&Severity::Error));
+            return match (query, error) {
+                (Some(query), None) => Ok(query),
+                (_, error) => Err(TemplaterError::Parse {
+                    file: INLINE_SOURCE.to_string(),
+                    line: 1,
+                    message: error
+                        .map(|d| d.message)
+                        .unwrap_or("invalid query".to_string()),
+                }),
+            };
+        }
+    }
+
+    impl QueryExpr {
+        // how tightly the expression binds when printed, single term groups print as their term
+        fn precedence(self: &Self) -> usize {
+            return match self {
+                QueryExpr::Or(terms) | QueryExpr::And(terms) if terms.len() == 1 => {
+                    terms[0].precedence()
+                }
+                QueryExpr::Or(_) => 1,
+                QueryExpr::And(_) => 2,
+                QueryExpr::Tag(_) | QueryExpr::Not(_) => 3,
+            };
+        }
+
+        fn fmt_within(self: &Self, f: &mut fmt::Formatter<'_>, precedence: usize) -> fmt::Result {
+            if self.precedence() < precedence {
+                return write!(f, "({})", self);
+            }
+            return write!(f, "{}", self);
+        }
+    }
+
+    impl fmt::Display for QueryExpr {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                QueryExpr::Tag(tag) => write!(f, "{}", tag),
+                QueryExpr::And(terms) | QueryExpr::Or(terms) => {
+                    let (separator, precedence) = match self {
+                        QueryExpr::And(_) => (" & ", 2),
+                        _ => (" | ", 1),
+                    };
+                    for (i, term) in terms.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, "{}", separator)?;
+                        }
+                        term.fmt_within(f, precedence)?;
+                    }
+                    return Ok(());
+                }
+                QueryExpr::Not(inner) => {
+                    write!(f, "!")?;
+                    return inner.fmt_within(f, 3);
+                }
+            }
+        }
+    }
+
+    impl fmt::Display for Query {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match &self.word_type {
+                Some(WordType::Noun) => write!(f, "{}[{}]", NOUN_WRAPPER, self.expr),
+                Some(WordType::Adjective) => write!(f, "{}[{}]", ADJECTIVE_WRAPPER, self.expr),
+                None => write!(f, "{}", self.expr),
+            }
+        }
+    }
+
+    impl Dictionary {
+        pub fn query_word_ids(self: &Self, query: &Query) -> BTreeSet<Uuid> {
+            let ids = &self.index.selection.word_ids;
+            return self
+                .index
+                .selection
+                .words(query)
+                .into_positions()
+                .map(|position| ids[position])
+                .collect();
+        }
+
+        pub fn query_template_ids(self: &Self, expr: &QueryExpr) -> BTreeSet<Uuid> {
+            let ids = &self.index.selection.template_ids;
+            return self
+                .index
+                .selection
+                .templates(expr)
+                .into_positions()
+                .map(|position| ids[position])
+                .collect();
+        }
+
+        // every matching word in id order, so results can be paged with skip and take
+        pub fn find_words<Q: Into<Query>>(self: &Self, query: Q) -> impl Iterator<Item = &Word> {
+            let ids = &self.index.selection.word_ids;
+            return self
+                .index
+                .selection
+                .words(&query.into())
+                .into_positions()
+                .map(|position| self.words.get(&ids[position]).unwrap());
+        }
+
+        pub fn count_words<Q: Into<Query>>(self: &Self, query: Q) -> usize {
+            return self.index.selection.words(&query.into()).count();
+        }
+
+        // every matching template in id order, the query's word type is ignored
+        pub fn find_templates<Q: Into<Query>>(
+            self: &Self,
+            query: Q,
+        ) -> impl Iterator<Item = &Template> {
+            let ids = &self.index.selection.template_ids;
+            return self
+                .index
+                .selection
+                .templates(&query.into().expr)
+                .into_positions()
+                .map(|position| self.templates.get(&ids[position]).unwrap());
+        }
+
+        pub fn get_random_word_matching<R: Rng + ?Sized>(
+            self: &Self,
+            query: &Query,
+            rng: &mut R,
+        ) -> Option<&Word> {
+            let selection = &self.index.selection;
+            let position =
+                selection.choose_word(selection.words(query), &self.distribution, rng)?;
+            return self.words.get(&selection.word_ids[position]);
+        }
+
+        // like get_random_word_matching but never returns one of the excluded words
+        pub fn get_random_word_excluding<R: Rng + ?Sized>(
+            self: &Self,
+            query: &Query,
+            excluded: &BTreeSet<Uuid>,
+            rng: &mut R,
+        ) -> Option<&Word> {
+            return self.get_random_word_with_forms(query, &[], excluded, rng);
+        }
+
+        // like get_random_word_excluding, also leaving out words without a related form of
+        // every given kind, so a slot bound as @name can give '@name.ADJECTIVE'
+        pub fn get_random_word_with_forms<R: Rng + ?Sized>(
+            self: &Self,
+            query: &Query,
+            forms: &[WordRelationType],
+            excluded: &BTreeSet<Uuid>,
+            rng: &mut R,
+        ) -> Option<&Word> {
+            let selection = &self.index.selection;
+            let matches = selection.with_forms(selection.words_excluding(query, excluded), forms);
+            let position = selection.choose_word(matches, &self.distribution, rng)?;
+            return self.words.get(&selection.word_ids[position]);
+        }
+
+        // why no word could ever fill the slot, None while some word matching the query
+        // has every one of the forms
+        pub fn unfillable(
+            self: &Self,
+            query: &Query,
+            forms: &[WordRelationType],
+        ) -> Option<TemplaterError> {
+            let selection = &self.index.selection;
+            if selection.words(query).count() == 0 {
+                return Some(TemplaterError::EmptySlot(query.clone()));
+            }
+            if selection.with_forms(selection.words(query), forms).count() > 0 {
+                return None;
+            }
+            let missing = forms.iter().find(|form| {
+                let form = std::slice::from_ref(*form);
+                return selection.with_forms(selection.words(query), form).count() == 0;
+            });
+            return Some(TemplaterError::NoWordWithForm {
+                query: query.clone(),
+                relation: missing.unwrap_or(&forms[0]).clone(),
+            });
+        }
+
+        pub fn try_get_random_word_matching<R: Rng + ?Sized>(
+            self: &Self,
+            query: &Query,
+            rng: &mut R,
+        ) -> Result<&Word, TemplaterError> {
+            return self
+                .get_random_word_matching(query, rng)
+                .ok_or(TemplaterError::EmptySlot(query.clone()));
+        }
+
+        pub fn get_random_template_matching<R: Rng + ?Sized>(
+            self: &Self,
+            expr: &QueryExpr,
+            rng: &mut R,
+        ) -> Option<&Template> {
+            let selection = &self.index.selection;
+            let position = selection.choose_template(expr, rng)?;
+            return self.templates.get(&selection.template_ids[position]);
+        }
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let tag = |t: &str| QueryExpr::Tag(t.to_string());
+        let query: Query = "Metal & (Colour | Gem) & !Cursed".parse().unwrap();
+        assert!(query.word_type.is_none());
+        assert!(query.expr.eq(&QueryExpr::And(vec![
+            tag("Metal"),
+            QueryExpr::Or(vec![tag("Colour"), tag("Gem")]),
+            QueryExpr::Not(Box::new(tag("Cursed"))),
+        ])));
+        assert!(query.to_string().eq("Metal & (Colour | Gem) & !Cursed"));
+
+        let query: Query = "ADJECTIVE[a | b & !(c | d)]".parse().unwrap();
+        assert!(query.word_type.eq(&Some(WordType::Adjective)));
+        assert!(query.expr.eq(&QueryExpr::Or(vec![
+            tag("a"),
+            QueryExpr::And(vec![
+                tag("b"),
+                QueryExpr::Not(Box::new(QueryExpr::Or(vec![tag("c"), tag("d")])))
+            ]),
+        ])));
+        assert!(query.to_string().eq("ADJECTIVE[a | b & !(c | d)]"));
+
+        let legacy: Query = "NOUN[[Large, Medium][Mammal]]".parse().unwrap();
+        assert!(legacy.eq(&Query::from((
+            WordType::Noun,
+            vec![
+                vec!["Large".to_string(), "Medium".to_string()],
+                vec!["Mammal".to_string()]
+            ]
+        ))));
+        assert!(legacy.to_string().eq("NOUN[(Large | Medium) & Mammal]"));
+
+        for (text, message) in [
+            ("Metal &", "expected a tag in query"),
+            ("(Metal | Gem", "unclosed '(' in query"),
+            ("Metal Gem", "unexpected text 'Gem' in query"),
+            (
+                "NOUN[Metal Gem]",
+                "expected '&', '|' or ']' in TEMPLATE slot",
+            ),
+        ] {
+            let error = text.parse::<Query>().unwrap_err();
+            assert!(matches!(error, TemplaterError::Parse { message: m, .. } if m.eq(message)));
+        }
+    }
+
+    #[test]
+    fn test_evaluate_query() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal), TAG(Grey)".to_string(),
+            "NOUN(Gold), TAG(Metal), TAG(Colour)".to_string(),
+            "NOUN(Ruby), TAG(Gem), TAG(Colour)".to_string(),
+            "NOUN(Cursed Gold), TAG(Metal), TAG(Colour), TAG(Cursed)".to_string(),
+            "NOUN(Oak), TAG(Wood)".to_string(),
+            "TEMPLATE(NOUN[Metal & !Cursed] Inn), TAG(Tavern)".to_string(),
+            "TEMPLATE(NOUN[Gem] Shop), TAG(Shop), TAG(Cursed)".to_string(),
+        ]);
+        let names = |query: &str| -> Vec<String> {
+            let query: Query = query.parse().unwrap();
+            let mut names: Vec<String> = dict
+                .query_word_ids(&query)
+                .iter()
+                .map(|id| dict.words.get(id).unwrap().base.clone())
+                .collect();
+            names.sort();
+            return names;
+        };
+        assert!(names("NOUN[Metal & (Colour | Gem) & !Cursed]").eq(&vec!["Gold"]));
+        assert!(names("NOUN[Metal | Gem]").len().eq(&4));
+        assert!(names("NOUN[!Metal]").eq(&vec!["Oak", "Ruby"]));
+        assert!(names("NOUN[!(Metal | Gem) | Grey]").eq(&vec!["Oak", "Steel"]));
+        assert!(names("Metal & Grey").eq(&vec!["Steel", "Steely"]));
+        assert!(names("NOUN[Unknown & Metal]").is_empty());
+        assert!(names("NOUN[!Unknown]").len().eq(&5));
+
+        let tavern: Query = "Tavern | Shop & !Cursed".parse().unwrap();
+        assert!(dict.query_template_ids(&tavern.expr).len().eq(&1));
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let template = dict
+                .get_random_template_matching(&tavern.expr, &mut rng)
+                .unwrap();
+            let rendered = dict.render_template(&template.id, &mut rng).unwrap();
+            assert!(rendered.eq("Steel Inn") || rendered.eq("Gold Inn"));
+        }
+        let missing: Query = "NOUN[Gem & Metal]".parse().unwrap();
+        assert!(matches!(
+            dict.try_get_random_word_matching(&missing, &mut rng),
+            Err(TemplaterError::EmptySlot(q)) if q.eq(&missing)
+        ));
+    }
+
+    #[test]
+    fn test_query_builder() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal), TAG(Grey)".to_string(),
+            "NOUN(Gold), TAG(Metal), TAG(Colour)".to_string(),
+            "NOUN(Ruby), TAG(Gem), TAG(Colour)".to_string(),
+            "NOUN(Cursed Gold), TAG(Metal), TAG(Colour), TAG(Cursed)".to_string(),
+        ]);
+        let built = Query::noun()
+            .all_of(["Metal"])
+            .any_of(["Colour", "Gem"])
+            .none_of(["Cursed"]);
+        let parsed: Query = "NOUN[Metal & (Colour | Gem) & !Cursed]".parse().unwrap();
+        assert!(built.to_string().eq(&parsed.to_string()));
+        assert!(dict
+            .query_word_ids(&built)
+            .eq(&dict.query_word_ids(&parsed)));
+        assert!(dict.query_word_ids(&Query::noun()).is_empty());
+        assert!(Query::noun().eq(&Query::from((WordType::Noun, Vec::new()))));
+        assert!(dict
+            .query_word_ids(&Query::noun().none_of(["Cursed"]))
+            .len()
+            .eq(&3));
+        assert!(dict
+            .query_word_ids(&Query::any().all_of(["Grey"]))
+            .len()
+            .eq(&2));
+
+        let legacy = Query::from((
+            WordType::Noun,
+            vec![vec!["Colour".to_string()], vec!["Metal".to_string()]],
+        ));
+        assert!(dict
+            .query_word_ids(&legacy)
+            .eq(&dict.query_word_ids(&Query::noun().all_of(["Colour", "Metal"]))));
+
+        let mut rng = rand::thread_rng();
+        assert!(dict
+            .get_random_word(Query::adjective().all_of(["Metal"]), &mut rng)
+            .unwrap()
+            .base
+            .eq("Steely"));
+        assert!(dict
+            .get_random_word(&parsed, &mut rng)
+            .unwrap()
+            .base
+            .eq("Gold"));
+        assert!(dict
+            .get_random_word_without(
+                Query::noun().all_of(["Colour"]),
+                vec!["Metal".to_string()],
+                &mut rng
+            )
+            .unwrap()
+            .base
+            .eq("Ruby"));
+    }
+
+    #[test]
+    fn test_find_and_count() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+            "NOUN(Copper), TAG(Metal)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+            "NOUN(Lead), TAG(Metal), TAG(Cursed)".to_string(),
+            "TEMPLATE(NOUN[Metal] Inn), TAG(Tavern)".to_string(),
+            "TEMPLATE(The NOUN[Metal] Forge), TAG(Shop)".to_string(),
+        ]);
+        let metals = Query::noun().all_of(["Metal"]).none_of(["Cursed"]);
+        assert!(dict.count_words(&metals).eq(&4));
+        assert!(dict.count_words(Query::any().all_of(["Metal"])).eq(&6));
+        assert!(dict.count_words(Query::noun().all_of(["Unknown"])).eq(&0));
+
+        let all: Vec<&Word> = dict.find_words(&metals).collect();
+        assert!(all.len().eq(&4));
+        assert!(all.windows(2).all(|w| w[0].id < w[1].id));
+        let pages: Vec<Vec<String>> = (0..2)
+            .map(|page| {
+                dict.find_words(&metals)
+                    .skip(page * 3)
+                    .take(3)
+                    .map(|w| w.base.clone())
+                    .collect()
+            })
+            .collect();
+        assert!(pages[0].len().eq(&3));
+        assert!(pages[1].len().eq(&1));
+        assert!(pages
+            .concat()
+            .eq(&all.iter().map(|w| w.base.clone()).collect::<Vec<String>>()));
+
+        let templates: Vec<&Template> = dict
+            .find_templates(Query::any().any_of(["Tavern", "Shop"]))
+            .collect();
+        assert!(templates.len().eq(&2));
+        assert!(dict
+            .find_templates(Query::any().all_of(["Tavern", "Shop"]))
+            .next()
+            .is_none());
+    }
+}
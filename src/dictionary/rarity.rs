@@ -0,0 +1,64 @@
+#[allow(clippy::module_inception, clippy::needless_return)]
+pub mod rarity {
+    use std::{collections::BTreeMap, fmt};
+
+    #[derive(PartialEq, Debug, Clone, Copy, Hash, Eq, PartialOrd, Ord, Default)]
+    pub enum Rarity {
+        #[default]
+        Common,
+        Uncommon,
+        Rare,
+        Legendary,
+    }
+
+    pub static RARITIES: [Rarity; 4] = [
+        Rarity::Common,
+        Rarity::Uncommon,
+        Rarity::Rare,
+        Rarity::Legendary,
+    ];
+
+    impl Rarity {
+        pub fn from_name(name: &str) -> Option<Rarity> {
+            return RARITIES.iter().copied().find(|r| r.to_string().eq(name));
+        }
+    }
+
+    impl fmt::Display for Rarity {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Rarity::Common => write!(f, "Common"),
+                Rarity::Uncommon => write!(f, "Uncommon"),
+                Rarity::Rare => write!(f, "Rare"),
+                Rarity::Legendary => write!(f, "Legendary"),
+            }
+        }
+    }
+
+    // how get_random_word picks among the words a query matches
+    #[derive(PartialEq, Debug, Clone, Default)]
+    pub enum Distribution {
+        // in proportion to WEIGHT, uniform when no word has one
+        #[default]
+        Weighted,
+        // a RARITY tier drawn with these odds from the tiers that have matches, then a
+        // weighted pick within it. Words without a RARITY are Common.
+        Tiers(BTreeMap<Rarity, f64>),
+        // matches ordered by RANK, unranked words last, with the position drawn from a Zipf
+        // distribution of this exponent so the first few dominate
+        Zipf(f64),
+    }
+
+    impl Distribution {
+        // 60/25/12/3 odds for Common through Legendary
+        pub fn tiers() -> Distribution {
+            return Distribution::Tiers(
+                RARITIES
+                    .iter()
+                    .copied()
+                    .zip([60.0, 25.0, 12.0, 3.0])
+                    .collect(),
+            );
+        }
+    }
+}
@@ -0,0 +1,797 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
+pub mod selection {
+    use std::{
+        borrow::Cow,
+        collections::{BTreeMap, BTreeSet},
+    };
+
+    use rand::Rng;
+    use rand_distr::{Distribution as _, WeightedIndex, Zipf};
+    use uuid::Uuid;
+
+    use crate::dictionary::{
+        query::query::{Query, QueryExpr},
+        rarity::rarity::{Distribution, Rarity},
+        template::template::Template,
+        word::word::{Word, WordRelationType, WordType},
+    };
+
+    // a set of dense positions, one bit each
+    #[derive(PartialEq, Debug, Clone, Default)]
+    pub struct BitSet {
+        blocks: Vec<u64>,
+    }
+
+    impl BitSet {
+        pub fn empty(len: usize) -> BitSet {
+            return BitSet {
+                blocks: vec![0; len.div_ceil(64)],
+            };
+        }
+
+        pub fn full(len: usize) -> BitSet {
+            let mut blocks = vec![u64::MAX; len.div_ceil(64)];
+            if let Some(last) = blocks.last_mut() {
+                if len % 64 != 0 {
+                    *last = (1 << (len % 64)) - 1;
+                }
+            }
+            return BitSet { blocks };
+        }
+
+        pub fn insert(self: &mut Self, position: usize) {
+            self.blocks[position / 64] |= 1 << (position % 64);
+        }
+
+        pub fn remove(self: &mut Self, position: usize) {
+            self.blocks[position / 64] &= !(1 << (position % 64));
+        }
+
+        pub fn contains(self: &Self, position: usize) -> bool {
+            return self
+                .blocks
+                .get(position / 64)
+                .is_some_and(|block| block & (1 << (position % 64)) != 0);
+        }
+
+        pub fn intersect(self: &mut Self, other: &BitSet) {
+            for (block, other) in self.blocks.iter_mut().zip(&other.blocks) {
+                *block &= other;
+            }
+        }
+
+        pub fn union(self: &mut Self, other: &BitSet) {
+            for (block, other) in self.blocks.iter_mut().zip(&other.blocks) {
+                *block |= other;
+            }
+        }
+
+        pub fn subtract(self: &mut Self, other: &BitSet) {
+            for (block, other) in self.blocks.iter_mut().zip(&other.blocks) {
+                *block &= !other;
+            }
+        }
+
+        pub fn count(self: &Self) -> usize {
+            return self.blocks.iter().map(|b| b.count_ones() as usize).sum();
+        }
+
+        // the n-th set position in ascending order
+        pub fn nth(self: &Self, mut n: usize) -> Option<usize> {
+            for (i, block) in self.blocks.iter().enumerate() {
+                let ones = block.count_ones() as usize;
+                if n >= ones {
+                    n -= ones;
+                    continue;
+                }
+                let mut block = *block;
+                for _ in 0..n {
+                    block &= block - 1;
+                }
+                return Some(i * 64 + block.trailing_zeros() as usize);
+            }
+            return None;
+        }
+
+        pub fn positions(self: &Self) -> impl Iterator<Item = usize> + '_ {
+            return self
+                .blocks
+                .iter()
+                .enumerate()
+                .flat_map(|(i, block)| block_positions(i, *block));
+        }
+
+        pub fn into_positions(self: Self) -> impl Iterator<Item = usize> {
+            return self
+                .blocks
+                .into_iter()
+                .enumerate()
+                .flat_map(|(i, block)| block_positions(i, block));
+        }
+    }
+
+    fn block_positions(index: usize, mut block: u64) -> impl Iterator<Item = usize> {
+        return std::iter::from_fn(move || {
+            if block == 0 {
+                return None;
+            }
+            let bit = block.trailing_zeros() as usize;
+            block &= block - 1;
+            return Some(index * 64 + bit);
+        });
+    }
+
+    // the members of one tag as both a sorted position list, for O(1) sampling, and a
+    // bitset, for intersecting with other tags
+    #[derive(PartialEq, Debug, Clone, Default)]
+    pub struct TagSet {
+        pub positions: Vec<usize>,
+        pub bits: BitSet,
+        // running weight totals over positions, None while the members weigh the same
+        pub cumulative: Option<Vec<f64>>,
+    }
+
+    impl TagSet {
+        fn build(len: usize, positions: Vec<usize>, weights: Option<&[f64]>) -> TagSet {
+            let mut bits = BitSet::empty(len);
+            for position in &positions {
+                bits.insert(*position);
+            }
+            let cumulative = weights.and_then(|w| running_totals(&positions, w));
+            return TagSet {
+                positions,
+                bits,
+                cumulative,
+            };
+        }
+    }
+
+    // what a query selected: a single precomputed tag, with its running weight totals when
+    // its members weigh differently, or the result of combining several
+    pub enum Matches<'a> {
+        Tag(&'a [usize], Option<&'a [f64]>),
+        Bits(BitSet),
+    }
+
+    impl<'a> Matches<'a> {
+        pub fn count(self: &Self) -> usize {
+            return match self {
+                Matches::Tag(positions, _) => positions.len(),
+                Matches::Bits(bits) => bits.count(),
+            };
+        }
+
+        pub fn contains(self: &Self, position: usize) -> bool {
+            return match self {
+                Matches::Tag(positions, _) => positions.binary_search(&position).is_ok(),
+                Matches::Bits(bits) => bits.contains(position),
+            };
+        }
+
+        pub fn nth(self: &Self, n: usize) -> Option<usize> {
+            return match self {
+                Matches::Tag(positions, _) => positions.get(n).copied(),
+                Matches::Bits(bits) => bits.nth(n),
+            };
+        }
+
+        pub fn into_positions(self: Self) -> Box<dyn Iterator<Item = usize> + 'a> {
+            return match self {
+                Matches::Tag(positions, _) => Box::new(positions.iter().copied()),
+                Matches::Bits(bits) => Box::new(bits.into_positions()),
+            };
+        }
+
+        // a pick in proportion to a tag's running weight totals when it has them, otherwise
+        // a uniform pick, drawing from the rng exactly as SliceRandom::choose would over the
+        // same matches in id order, so seeded output does not depend on how they were found
+        pub fn choose<R: Rng + ?Sized>(self: &Self, rng: &mut R) -> Option<usize> {
+            if let Matches::Tag(positions, Some(cumulative)) = self {
+                let total = cumulative.last().copied().unwrap_or(0.0);
+                if total <= 0.0 {
+                    return None;
+                }
+                let target = rng.gen_range(0.0..total);
+                let index = cumulative.partition_point(|c| *c <= target);
+                // rounding can leave a sliver past the last weight
+                return positions.get(index.min(positions.len() - 1)).copied();
+            }
+            let len = self.count();
+            if len == 0 {
+                return None;
+            }
+            if len <= u32::MAX as usize {
+                return self.nth(rng.gen_range(0..len as u32) as usize);
+            }
+            return self.nth(rng.gen_range(0..len));
+        }
+    }
+
+    // dense positions for every word and template in id order, built once with the index
+    #[derive(PartialEq, Debug, Clone, Default)]
+    pub struct SelectionIndex {
+        pub word_ids: Vec<Uuid>,
+        pub template_ids: Vec<Uuid>,
+        // None while every weight is 1, so unweighted dictionaries keep the uniform pick
+        word_weights: Option<Vec<f64>>,
+        template_weights: Option<Vec<f64>>,
+        word_rarities: Vec<Rarity>,
+        word_ranks: Vec<Option<u64>>,
+        // positions of the ranked words, lowest rank first, so a Zipf pick only filters it
+        word_ranked: Vec<usize>,
+        word_types: BTreeMap<WordType, TagSet>,
+        // the words with a related form of each kind, for '@name.ADJECTIVE' and '@name.NOUN'
+        word_forms: BTreeMap<WordRelationType, BitSet>,
+        tag_words: BTreeMap<(WordType, String), TagSet>,
+        tag_templates: BTreeMap<String, TagSet>,
+    }
+
+    impl SelectionIndex {
+        pub fn build(
+            words: &BTreeMap<Uuid, Word>,
+            templates: &BTreeMap<Uuid, Template>,
+        ) -> SelectionIndex {
+            let mut word_types: BTreeMap<WordType, Vec<usize>> = BTreeMap::new();
+            let mut tag_words: BTreeMap<(WordType, String), Vec<usize>> = BTreeMap::new();
+            let mut word_forms: BTreeMap<WordRelationType, BitSet> = BTreeMap::new();
+            for (position, word) in words.values().enumerate() {
+                for (relation, id) in &word.related {
+                    if words.contains_key(id) {
+                        word_forms
+                            .entry(relation.clone())
+                            .or_insert_with(|| BitSet::empty(words.len()))
+                            .insert(position);
+                    }
+                }
+                word_types
+                    .entry(word.word_type.clone())
+                    .or_default()
+                    .push(position);
+                for tag in &word.tags {
+                    tag_words
+                        .entry((word.word_type.clone(), tag.clone()))
+                        .or_default()
+                        .push(position);
+                }
+            }
+            let mut tag_templates: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+            for (position, template) in templates.values().enumerate() {
+                for tag in &template.tags {
+                    tag_templates.entry(tag.clone()).or_default().push(position);
+                }
+            }
+            let (word_count, template_count) = (words.len(), templates.len());
+            let mut word_ranked: Vec<usize> = (0..words.len()).collect();
+            let ranks: Vec<Option<u64>> = words.values().map(|w| w.rank).collect();
+            word_ranked.retain(|p| ranks[*p].is_some());
+            word_ranked.sort_by_key(|p| ranks[*p]);
+            let word_weights = weights(words.values().map(|w| w.weight));
+            let template_weights = weights(templates.values().map(|t| t.weight));
+            return SelectionIndex {
+                word_ids: words.keys().cloned().collect(),
+                template_ids: templates.keys().cloned().collect(),
+                word_rarities: words.values().map(|w| w.rarity).collect(),
+                word_ranks: ranks,
+                word_ranked,
+                word_forms,
+                // only ever combined as bits, so never sampled with weights
+                word_types: word_types
+                    .into_iter()
+                    .map(|(k, v)| (k, TagSet::build(word_count, v, None)))
+                    .collect(),
+                tag_words: tag_words
+                    .into_iter()
+                    .map(|(k, v)| (k, TagSet::build(word_count, v, word_weights.as_deref())))
+                    .collect(),
+                tag_templates: tag_templates
+                    .into_iter()
+                    .map(|(k, v)| {
+                        let weights = template_weights.as_deref();
+                        (k, TagSet::build(template_count, v, weights))
+                    })
+                    .collect(),
+                word_weights,
+                template_weights,
+            };
+        }
+
+        pub fn words(self: &Self, query: &Query) -> Matches<'_> {
+            let len = self.word_ids.len();
+            if let (Some(word_type), QueryExpr::Tag(tag)) = (&query.word_type, &query.expr) {
+                return match self.tag_words.get(&(word_type.clone(), tag.clone())) {
+                    Some(set) => Matches::Tag(&set.positions, set.cumulative.as_deref()),
+                    None => Matches::Tag(&[], None),
+                };
+            }
+            let word_types = match &query.word_type {
+                Some(word_type) => vec![word_type.clone()],
+                None => vec![WordType::Noun, WordType::Adjective],
+            };
+            let universe = union(len, word_types.iter().map(|t| self.word_types.get(t)));
+            let lookup = |tag: &str| -> Cow<'_, BitSet> {
+                return union(
+                    len,
+                    word_types
+                        .iter()
+                        .map(|t| self.tag_words.get(&(t.clone(), tag.to_string()))),
+                );
+            };
+            return Matches::Bits(evaluate(&query.expr, &lookup, &universe));
+        }
+
+        pub fn templates(self: &Self, expr: &QueryExpr) -> Matches<'_> {
+            let len = self.template_ids.len();
+            if let QueryExpr::Tag(tag) = expr {
+                return match self.tag_templates.get(tag) {
+                    Some(set) => Matches::Tag(&set.positions, set.cumulative.as_deref()),
+                    None => Matches::Tag(&[], None),
+                };
+            }
+            let empty = BitSet::empty(len);
+            let lookup = |tag: &str| -> Cow<'_, BitSet> {
+                return Cow::Borrowed(
+                    self.tag_templates
+                        .get(tag)
+                        .map(|set| &set.bits)
+                        .unwrap_or(&empty),
+                );
+            };
+            return Matches::Bits(evaluate(expr, &lookup, &BitSet::full(len)));
+        }
+
+        // the query's matches without the given words, for sampling without replacement
+        pub fn words_excluding(
+            self: &Self,
+            query: &Query,
+            excluded: &BTreeSet<Uuid>,
+        ) -> Matches<'_> {
+            let matches = self.words(query);
+            if excluded.is_empty() {
+                return matches;
+            }
+            let mut bits = BitSet::empty(self.word_ids.len());
+            for position in matches.into_positions() {
+                bits.insert(position);
+            }
+            for id in excluded {
+                if let Ok(position) = self.word_ids.binary_search(id) {
+                    bits.remove(position);
+                }
+            }
+            return Matches::Bits(bits);
+        }
+
+        // the matches that have a related form of every given kind
+        pub fn with_forms<'a>(
+            self: &Self,
+            matches: Matches<'a>,
+            forms: &[WordRelationType],
+        ) -> Matches<'a> {
+            if forms.is_empty() {
+                return matches;
+            }
+            let mut bits = match matches {
+                Matches::Bits(bits) => bits,
+                matches => {
+                    let mut bits = BitSet::empty(self.word_ids.len());
+                    for position in matches.into_positions() {
+                        bits.insert(position);
+                    }
+                    bits
+                }
+            };
+            for form in forms {
+                match self.word_forms.get(form) {
+                    Some(with_form) => bits.intersect(with_form),
+                    None => bits = BitSet::empty(self.word_ids.len()),
+                }
+            }
+            return Matches::Bits(bits);
+        }
+
+        pub fn choose_word<R: Rng + ?Sized>(
+            self: &Self,
+            matches: Matches<'_>,
+            distribution: &Distribution,
+            rng: &mut R,
+        ) -> Option<usize> {
+            return match distribution {
+                Distribution::Weighted => pick(matches, self.word_weights.as_deref(), rng),
+                Distribution::Tiers(odds) => self.choose_tier(matches, odds, rng),
+                Distribution::Zipf(exponent) => self.choose_ranked(matches, *exponent, rng),
+            };
+        }
+
+        pub fn choose_template<R: Rng + ?Sized>(
+            self: &Self,
+            expr: &QueryExpr,
+            rng: &mut R,
+        ) -> Option<usize> {
+            return pick(self.templates(expr), self.template_weights.as_deref(), rng);
+        }
+
+        // falls back to a weighted pick when none of the matched tiers has any odds
+        fn choose_tier<R: Rng + ?Sized>(
+            self: &Self,
+            matches: Matches<'_>,
+            odds: &BTreeMap<Rarity, f64>,
+            rng: &mut R,
+        ) -> Option<usize> {
+            let positions: Vec<usize> = matches.into_positions().collect();
+            let mut tiers: BTreeMap<Rarity, Vec<usize>> = BTreeMap::new();
+            for position in &positions {
+                tiers
+                    .entry(self.word_rarities[*position])
+                    .or_default()
+                    .push(*position);
+            }
+            let odds: Vec<f64> = tiers
+                .keys()
+                .map(|rarity| odds.get(rarity).copied().unwrap_or(0.0))
+                .collect();
+            let tier = match WeightedIndex::new(&odds) {
+                Ok(tier) => tiers.values().nth(tier.sample(rng)).unwrap(),
+                Err(_) => &positions,
+            };
+            let cumulative = self
+                .word_weights
+                .as_deref()
+                .and_then(|w| running_totals(tier, w));
+            return Matches::Tag(tier, cumulative.as_deref()).choose(rng);
+        }
+
+        // an exponent below zero is treated as zero, a uniform pick over the ranks
+        fn choose_ranked<R: Rng + ?Sized>(
+            self: &Self,
+            matches: Matches<'_>,
+            exponent: f64,
+            rng: &mut R,
+        ) -> Option<usize> {
+            // ranked matches in rank order, then the unranked ones in id order
+            let mut positions: Vec<usize> = self
+                .word_ranked
+                .iter()
+                .copied()
+                .filter(|p| matches.contains(*p))
+                .collect();
+            positions.extend(
+                matches
+                    .into_positions()
+                    .filter(|p| self.word_ranks[*p].is_none()),
+            );
+            if positions.is_empty() {
+                return None;
+            }
+            let zipf = Zipf::new(positions.len() as u64, exponent.max(0.0)).ok()?;
+            let rank = zipf.sample(rng) as usize;
+            return positions.get(rank.clamp(1, positions.len()) - 1).copied();
+        }
+    }
+
+    fn weights(values: impl Iterator<Item = f64>) -> Option<Vec<f64>> {
+        let values: Vec<f64> = values.collect();
+        if values.iter().all(|w| *w == 1.0) {
+            return None;
+        }
+        return Some(values);
+    }
+
+    // None when the positions all weigh the same above zero, so a uniform pick will do
+    fn running_totals(positions: &[usize], weights: &[f64]) -> Option<Vec<f64>> {
+        let first = positions.first().map(|p| weights[*p])?;
+        if first > 0.0 && positions.iter().all(|p| weights[*p] == first) {
+            return None;
+        }
+        let mut total = 0.0;
+        return Some(
+            positions
+                .iter()
+                .map(|p| {
+                    total += weights[*p];
+                    total
+                })
+                .collect(),
+        );
+    }
+
+    // a single tag carries its own totals, so only combined matches scan the weights
+    fn pick<R: Rng + ?Sized>(
+        matches: Matches<'_>,
+        weights: Option<&[f64]>,
+        rng: &mut R,
+    ) -> Option<usize> {
+        return match (matches, weights) {
+            (Matches::Bits(bits), Some(weights)) => {
+                let positions: Vec<usize> = bits.into_positions().collect();
+                let cumulative = running_totals(&positions, weights);
+                Matches::Tag(&positions, cumulative.as_deref()).choose(rng)
+            }
+            (matches, _) => matches.choose(rng),
+        };
+    }
+
+    // borrows when only one of the sets exists
+    fn union<'a>(len: usize, sets: impl Iterator<Item = Option<&'a TagSet>>) -> Cow<'a, BitSet> {
+        let mut sets: Vec<&TagSet> = sets.flatten().collect();
+        if sets.len() == 1 {
+            return Cow::Borrowed(&sets.pop().unwrap().bits);
+        }
+        let mut output = BitSet::empty(len);
+        for set in sets {
+            output.union(&set.bits);
+        }
+        return Cow::Owned(output);
+    }
+
+    // '!' is the complement within the universe, except directly inside '&' where it only
+    // removes from the other terms
+    fn evaluate<'a>(
+        expr: &QueryExpr,
+        lookup: &dyn Fn(&str) -> Cow<'a, BitSet>,
+        universe: &BitSet,
+    ) -> BitSet {
+        return match expr {
+            QueryExpr::Tag(tag) => lookup(tag).into_owned(),
+            QueryExpr::Or(terms) => {
+                let mut output = BitSet::empty(universe.blocks.len() * 64);
+                for term in terms {
+                    output.union(&evaluate(term, lookup, universe));
+                }
+                output
+            }
+            QueryExpr::Not(inner) => {
+                let mut output = universe.clone();
+                output.subtract(&evaluate(inner, lookup, universe));
+                output
+            }
+            QueryExpr::And(terms) => {
+                let (negated, required): (Vec<&QueryExpr>, Vec<&QueryExpr>) = terms
+                    .iter()
+                    .partition(|term| matches!(term, QueryExpr::Not(_)));
+                let mut output = match required.first() {
+                    Some(first) => evaluate(first, lookup, universe),
+                    None => universe.clone(),
+                };
+                for term in required.iter().skip(1) {
+                    output.intersect(&evaluate(term, lookup, universe));
+                }
+                for term in negated {
+                    if let QueryExpr::Not(inner) = term {
+                        output.subtract(&evaluate(inner, lookup, universe));
+                    }
+                }
+                output
+            }
+        };
+    }
+
+    #[test]
+    fn test_bit_set() {
+        let mut bits = BitSet::empty(200);
+        for position in [0, 3, 63, 64, 130, 199] {
+            bits.insert(position);
+        }
+        assert!(bits.count().eq(&6));
+        assert!(bits.contains(130) && !bits.contains(131));
+        assert!(bits.nth(3).eq(&Some(64)));
+        assert!(bits.nth(5).eq(&Some(199)));
+        assert!(bits.nth(6).is_none());
+        assert!(bits
+            .positions()
+            .collect::<Vec<usize>>()
+            .eq(&vec![0, 3, 63, 64, 130, 199]));
+
+        let mut other = BitSet::full(200);
+        other.subtract(&bits);
+        assert!(other.count().eq(&194));
+        other.intersect(&bits);
+        assert!(other.count().eq(&0));
+        other.union(&bits);
+        assert!(other.eq(&bits));
+
+        for len in [0, 1, 63, 64, 65, 200] {
+            let full = BitSet::full(len);
+            assert!(full.count().eq(&len));
+            assert!(!full.contains(len));
+            assert!(full.positions().eq(0..len));
+        }
+    }
+
+    #[test]
+    fn test_choose_matches_slice_choose() {
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+        let positions: Vec<usize> = (0..1000).filter(|p| p % 7 == 0).collect();
+        let mut bits = BitSet::empty(1000);
+        for position in &positions {
+            bits.insert(*position);
+        }
+        let (mut a, mut b, mut c) = (
+            StdRng::seed_from_u64(9),
+            StdRng::seed_from_u64(9),
+            StdRng::seed_from_u64(9),
+        );
+        for _ in 0..100 {
+            let expected = positions.choose(&mut a).copied();
+            assert!(Matches::Tag(&positions, None).choose(&mut b).eq(&expected));
+            assert!(Matches::Bits(bits.clone()).choose(&mut c).eq(&expected));
+        }
+        assert!(Matches::Tag(&[], None).choose(&mut a).is_none());
+
+        // 12 adds three times what 5 does to the running totals, 3 and 9 add nothing
+        let weighted = Matches::Tag(&[3, 5, 9, 12], Some(&[0.0, 1.0, 1.0, 4.0]));
+        let picks: Vec<usize> = (0..400).filter_map(|_| weighted.choose(&mut a)).collect();
+        assert!(picks.iter().all(|p| [5, 12].contains(p)));
+        assert!((250..350).contains(&picks.iter().filter(|p| **p == 12).count()));
+        assert!(Matches::Tag(&[3], Some(&[0.0])).choose(&mut a).is_none());
+    }
+
+    #[test]
+    fn test_weighted_selection() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal), WEIGHT(9), TEMPLATE(Steel Inn), TAG(Inn)".to_string(),
+            "NOUN(Gold), TAG(Metal), TEMPLATE(Gold Inn), TAG(Inn)".to_string(),
+            "NOUN(Oak), TAG(Wood), WEIGHT(0.5)".to_string(),
+        ]);
+        let mut rng = StdRng::seed_from_u64(5);
+        let query: Query = "NOUN[Metal]".parse().unwrap();
+        let compound: Query = "NOUN[Metal & !Wood]".parse().unwrap();
+        let (mut steel, mut compound_steel, mut steel_inn) = (0, 0, 0);
+        for _ in 0..2000 {
+            if dict
+                .get_random_word(&query, &mut rng)
+                .unwrap()
+                .base
+                .eq("Steel")
+            {
+                steel += 1;
+            }
+            if dict
+                .get_random_word(&compound, &mut rng)
+                .unwrap()
+                .base
+                .eq("Steel")
+            {
+                compound_steel += 1;
+            }
+            let template = dict.get_random_template(Query::any().all_of(["Inn"]), &mut rng);
+            if dict
+                .render_template(&template.unwrap().id, &mut rng)
+                .unwrap()
+                .eq("Steel Inn")
+            {
+                steel_inn += 1;
+            }
+        }
+        for count in [steel, compound_steel, steel_inn] {
+            assert!((1700..1900).contains(&count));
+        }
+
+        // only tags whose members weigh differently keep running totals
+        let selection = &dict.index.selection;
+        assert!(matches!(selection.words(&query), Matches::Tag(_, Some(_))));
+        let wood: Query = "NOUN[Wood]".parse().unwrap();
+        assert!(matches!(selection.words(&wood), Matches::Tag(_, None)));
+    }
+
+    #[test]
+    fn test_distribution_selection() {
+        use crate::dictionary::dictionary::{build_dictionary, Dictionary};
+        use rand::{rngs::StdRng, SeedableRng};
+        use std::collections::BTreeMap;
+        let mut dict = build_dictionary(vec![
+            "NOUN(Iron), TAG(Metal), RANK(1)".to_string(),
+            "NOUN(Copper), TAG(Metal), RANK(2)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+            "NOUN(Silver), TAG(Metal), RARITY(Rare)".to_string(),
+            "NOUN(Mithril), TAG(Metal), RARITY(Legendary), RANK(3)".to_string(),
+        ]);
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut draw = |dict: &Dictionary| -> BTreeMap<String, usize> {
+            let mut output: BTreeMap<String, usize> = BTreeMap::new();
+            for _ in 0..3000 {
+                let word = dict.get_random_word(Query::noun().all_of(["Metal"]), &mut rng);
+                *output.entry(word.unwrap().base.clone()).or_default() += 1;
+            }
+            return output;
+        };
+
+        // a third of the draws for each tier with matches, split evenly within a tier
+        dict.distribution = Distribution::Tiers(
+            [
+                (Rarity::Common, 1.0),
+                (Rarity::Rare, 1.0),
+                (Rarity::Legendary, 1.0),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let tiers = draw(&dict);
+        assert!((900..1100).contains(&tiers["Silver"]));
+        assert!((900..1100).contains(&tiers["Mithril"]));
+        assert!((250..420).contains(&tiers["Tin"]));
+
+        dict.distribution = Distribution::Tiers(BTreeMap::new());
+        assert!(draw(&dict).len().eq(&5));
+
+        // Zipf(5, 1) gives the ranks 1/1 : 1/2 : 1/3 : 1/4 : 1/5 of the draws
+        dict.distribution = Distribution::Zipf(1.0);
+        let ranked = draw(&dict);
+        assert!(ranked["Iron"] > ranked["Copper"] && ranked["Copper"] > ranked["Mithril"]);
+        assert!((1200..1420).contains(&ranked["Iron"]));
+        assert!(ranked["Tin"] + ranked["Silver"] < ranked["Iron"]);
+    }
+
+    #[test]
+    fn test_selection_agrees_with_tags() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+        use std::collections::BTreeSet;
+
+        fn matches(expr: &QueryExpr, tags: &BTreeSet<String>) -> bool {
+            return match expr {
+                QueryExpr::Tag(tag) => tags.contains(tag),
+                QueryExpr::And(terms) => terms.iter().all(|t| matches(t, tags)),
+                QueryExpr::Or(terms) => terms.iter().any(|t| matches(t, tags)),
+                QueryExpr::Not(inner) => !matches(inner, tags),
+            };
+        }
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let tags = ["A", "B", "C", "D", "E"];
+        let mut lines: Vec<String> = Vec::new();
+        for i in 0..300 {
+            let word_type = ["NOUN", "ADJECTIVE"].choose(&mut rng).unwrap();
+            let mut line = format!("{}(W{})", word_type, i);
+            for tag in tags.choose_multiple(&mut rng, 2) {
+                line.push_str(&format!(", TAG({})", tag));
+            }
+            if i % 3 == 0 {
+                line.push_str(&format!(", TEMPLATE(T{} NOUN[A])", i));
+            }
+            lines.push(line);
+        }
+        let dict = build_dictionary(lines);
+        for text in [
+            "NOUN[A]",
+            "A",
+            "NOUN[A & B]",
+            "ADJECTIVE[A | !B]",
+            "NOUN[!(A | C) & !D]",
+            "!E",
+            "NOUN[Unknown | C & !A]",
+            "NOUN[!Unknown]",
+        ] {
+            let query: Query = text.parse().unwrap();
+            let expected: Vec<Uuid> = dict
+                .words
+                .values()
+                .filter(|w| {
+                    query
+                        .word_type
+                        .as_ref()
+                        .map_or(true, |t| t.eq(&w.word_type))
+                })
+                .filter(|w| matches(&query.expr, &w.tags))
+                .map(|w| w.id)
+                .collect();
+            let found: Vec<Uuid> = dict.find_words(&query).map(|w| w.id).collect();
+            assert!(found.eq(&expected));
+            assert!(dict.count_words(&query).eq(&expected.len()));
+
+            let expected: Vec<Uuid> = dict
+                .templates
+                .values()
+                .filter(|t| matches(&query.expr, &t.tags))
+                .map(|t| t.id)
+                .collect();
+            let found: Vec<Uuid> = dict.find_templates(&query).map(|t| t.id).collect();
+            assert!(found.eq(&expected));
+        }
+    }
+}
@@ -0,0 +1,595 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
+pub mod session {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        fmt,
+        str::FromStr,
+    };
+
+    use rand::Rng;
+    use uuid::Uuid;
+
+    use crate::{
+        dictionary::{
+            dictionary::{Dictionary, INLINE_SOURCE},
+            query::query::Query,
+            word::word::{Word, WordRelationType},
+        },
+        error::error::TemplaterError,
+    };
+
+    // what a session has handed out, kept apart from the session so it can be saved and
+    // resumed against the same dictionary later
+    #[derive(PartialEq, Debug, Clone, Default)]
+    pub struct SessionState {
+        // word ids drawn so far, per query in its text form so that a built query and
+        // the same query parsed from text share a history
+        pub used: BTreeMap<String, BTreeSet<Uuid>>,
+        pub rendered: BTreeSet<String>,
+        // the render each word was last drawn in, for RecentWindow, kept only as far back
+        // as the widest window
+        pub recent: BTreeMap<Uuid, usize>,
+        // calls to get_random_word, render_template and as_one_render so far
+        pub renders: usize,
+    }
+
+    // a softer limit than uniqueness: words drawn in the last `renders` renders are picked
+    // with their odds scaled by `weight`, so 0 leaves them out while anything else is free
+    #[derive(PartialEq, Debug, Clone, Copy)]
+    pub struct RecentWindow {
+        pub renders: usize,
+        // from 0 to 1
+        pub weight: f64,
+    }
+
+    // draws from a dictionary without repeating itself: by default a query never gives the
+    // same word twice and a template never renders the same text twice
+    pub struct GenerationSession<'a> {
+        dict: &'a Dictionary,
+        state: SessionState,
+        // the words each RecentWindow applies to
+        windows: Vec<(BTreeSet<Uuid>, RecentWindow)>,
+        // set inside as_one_render, where draws wait to be counted together
+        grouped: bool,
+        // when set, get_random_word draws without replacement
+        pub unique_words: bool,
+        // when set, template slots also draw their words without replacement
+        pub unique_slot_words: bool,
+        // when set, render_template never gives the same text twice
+        pub unique_renders: bool,
+        // renders tried before a template is reported as exhausted
+        pub attempts: usize,
+    }
+
+    impl<'a> GenerationSession<'a> {
+        pub fn new(dict: &'a Dictionary) -> Self {
+            return GenerationSession::resume(dict, SessionState::default());
+        }
+
+        pub fn resume(dict: &'a Dictionary, state: SessionState) -> Self {
+            return GenerationSession {
+                dict,
+                state,
+                windows: Vec::new(),
+                grouped: false,
+                unique_words: true,
+                unique_slot_words: false,
+                unique_renders: true,
+                attempts: 100,
+            };
+        }
+
+        pub fn state(self: &Self) -> &SessionState {
+            return &self.state;
+        }
+
+        pub fn reset(self: &mut Self) {
+            self.state = SessionState::default();
+        }
+
+        // makes every word of one query available again, other queries are untouched
+        pub fn reset_query<Q: Into<Query>>(self: &mut Self, query: Q) {
+            self.state.used.remove(&query.into().to_string());
+        }
+
+        // applies the window to every word the query matches, however it is drawn, so a
+        // single tag query such as "LastName" covers names and template slots alike
+        pub fn suppress_recent<Q: Into<Query>>(self: &mut Self, query: Q, window: RecentWindow) {
+            let ids = self.dict.query_word_ids(&query.into());
+            self.windows.push((ids, window));
+        }
+
+        // counts every word drawn inside as a single render, such as the two halves of a name
+        pub fn as_one_render<T>(self: &mut Self, draws: impl FnOnce(&mut Self) -> T) -> T {
+            let grouped = std::mem::replace(&mut self.grouped, true);
+            let output = draws(self);
+            self.grouped = grouped;
+            if !grouped {
+                self.end_render();
+            }
+            return output;
+        }
+
+        // how many words the query can still give before it is exhausted
+        pub fn remaining<Q: Into<Query>>(self: &Self, query: Q) -> usize {
+            let query = query.into();
+            return self
+                .dict
+                .index
+                .selection
+                .words_excluding(&query, &self.used(&query))
+                .count();
+        }
+
+        pub fn try_get_random_word<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &mut Self,
+            query: Q,
+            rng: &mut R,
+        ) -> Result<&'a Word, TemplaterError> {
+            let query = query.into();
+            let excluded = match self.unique_words {
+                true => self.used(&query),
+                false => BTreeSet::new(),
+            };
+            let word = self.draw(&query, &[], &excluded, rng)?;
+            if self.unique_words {
+                self.state
+                    .used
+                    .entry(query.to_string())
+                    .or_default()
+                    .insert(word.id);
+            }
+            self.record([word.id]);
+            return Ok(word);
+        }
+
+        pub fn get_random_word<Q: Into<Query>, R: Rng + ?Sized>(
+            self: &mut Self,
+            query: Q,
+            rng: &mut R,
+        ) -> Option<&'a Word> {
+            return self.try_get_random_word(query, rng).ok();
+        }
+
+        // words drawn by a render that turns out to be a repeat are given back
+        pub fn try_render_template<R: Rng + ?Sized>(
+            self: &mut Self,
+            template_id: &Uuid,
+            rng: &mut R,
+        ) -> Result<String, TemplaterError> {
+            let dict = self.dict;
+            for _ in 0..self.attempts {
+                let mut drawn: BTreeMap<String, BTreeSet<Uuid>> = BTreeMap::new();
+                let text =
+                    dict.try_render_template_with(template_id, rng, |query, forms, rng| {
+                        let key = query.to_string();
+                        let mut excluded = BTreeSet::new();
+                        if self.unique_slot_words {
+                            excluded.extend(self.used(query));
+                            excluded.extend(drawn.get(&key).into_iter().flatten());
+                        }
+                        let word = self.draw(query, forms, &excluded, rng)?;
+                        drawn.entry(key).or_default().insert(word.id);
+                        return Ok(word);
+                    })?;
+                if !self.unique_renders || self.state.rendered.insert(text.clone()) {
+                    let ids: Vec<Uuid> = drawn.values().flatten().copied().collect();
+                    if self.unique_slot_words {
+                        for (query, ids) in drawn {
+                            self.state.used.entry(query).or_default().extend(ids);
+                        }
+                    }
+                    self.record(ids);
+                    return Ok(text);
+                }
+            }
+            return Err(TemplaterError::ExhaustedTemplate(*template_id));
+        }
+
+        pub fn render_template<R: Rng + ?Sized>(
+            self: &mut Self,
+            template_id: &Uuid,
+            rng: &mut R,
+        ) -> Option<String> {
+            return self.try_render_template(template_id, rng).ok();
+        }
+
+        // recently drawn words are left out while anything else matches, and otherwise
+        // redrawn with the chance of their window's weight
+        fn draw<R: Rng + ?Sized>(
+            self: &Self,
+            query: &Query,
+            forms: &[WordRelationType],
+            excluded: &BTreeSet<Uuid>,
+            rng: &mut R,
+        ) -> Result<&'a Word, TemplaterError> {
+            let mut suppressed: BTreeMap<Uuid, f64> = BTreeMap::new();
+            for (ids, window) in &self.windows {
+                for (id, render) in &self.state.recent {
+                    if self.state.renders.saturating_sub(*render) <= window.renders
+                        && ids.contains(id)
+                    {
+                        let weight = suppressed.entry(*id).or_insert(1.0);
+                        *weight = weight.min(window.weight.clamp(0.0, 1.0));
+                    }
+                }
+            }
+            let mut strict = excluded.clone();
+            strict.extend(
+                suppressed
+                    .iter()
+                    .filter(|(_, w)| **w == 0.0)
+                    .map(|(id, _)| *id),
+            );
+            let selection = &self.dict.index.selection;
+            let excluded = match selection
+                .with_forms(selection.words_excluding(query, &strict), forms)
+                .count()
+            {
+                0 => excluded,
+                _ => &strict,
+            };
+            for _ in 0..self.attempts {
+                let word = draw(self.dict, query, forms, excluded, rng)?;
+                match suppressed.get(&word.id) {
+                    Some(weight) if !rng.gen_bool(*weight) => continue,
+                    _ => return Ok(word),
+                }
+            }
+            return draw(self.dict, query, forms, excluded, rng);
+        }
+
+        fn record(self: &mut Self, ids: impl IntoIterator<Item = Uuid>) {
+            for id in ids {
+                self.state.recent.insert(id, self.state.renders);
+            }
+            if !self.grouped {
+                self.end_render();
+            }
+        }
+
+        // forgets the words that have fallen out of every window
+        fn end_render(self: &mut Self) {
+            self.state.renders += 1;
+            let renders = self.state.renders;
+            let widest = self.windows.iter().map(|(_, w)| w.renders).max();
+            self.state
+                .recent
+                .retain(|_, render| renders.saturating_sub(*render) <= widest.unwrap_or(0));
+        }
+
+        fn used(self: &Self, query: &Query) -> BTreeSet<Uuid> {
+            return self
+                .state
+                .used
+                .get(&query.to_string())
+                .cloned()
+                .unwrap_or_default();
+        }
+    }
+
+    // EmptySlot or NoWordWithForm when nothing could ever fill the slot, Exhausted when
+    // everything that could is used
+    fn draw<'a, R: Rng + ?Sized>(
+        dict: &'a Dictionary,
+        query: &Query,
+        forms: &[WordRelationType],
+        excluded: &BTreeSet<Uuid>,
+        rng: &mut R,
+    ) -> Result<&'a Word, TemplaterError> {
+        if let Some(word) = dict.get_random_word_with_forms(query, forms, excluded, rng) {
+            return Ok(word);
+        }
+        if let Some(error) = dict.unfillable(query, forms) {
+            return Err(error);
+        }
+        return Err(TemplaterError::Exhausted(query.clone()));
+    }
+
+    // a query key with '\', tabs and line breaks escaped, as its tags may hold anything
+    fn escape_key(key: &str) -> String {
+        let mut output = String::new();
+        for c in key.chars() {
+            match c {
+                '\\' => output.push_str("\\\\"),
+                '\t' => output.push_str("\\t"),
+                '\n' => output.push_str("\\n"),
+                '\r' => output.push_str("\\r"),
+                c => output.push(c),
+            }
+        }
+        return output;
+    }
+
+    fn unescape_key(text: &str) -> String {
+        let mut output = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                output.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('t') => output.push('\t'),
+                Some('n') => output.push('\n'),
+                Some('r') => output.push('\r'),
+                Some(c) => output.push(c),
+                None => output.push('\\'),
+            }
+        }
+        return output;
+    }
+
+    // one tab separated record per line: "USED <query> <id>", "RENDERED <text>",
+    // "RECENT <id> <render>" or "RENDERS <count>", where the query is escaped and kept as
+    // written rather than parsed, since its tags need not survive the query syntax
+    impl fmt::Display for SessionState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (query, ids) in &self.used {
+                for id in ids {
+                    writeln!(f, "USED\t{}\t{}", escape_key(query), id)?;
+                }
+            }
+            for text in &self.rendered {
+                writeln!(f, "RENDERED\t{}", text)?;
+            }
+            for (id, render) in &self.recent {
+                writeln!(f, "RECENT\t{}\t{}", id, render)?;
+            }
+            writeln!(f, "RENDERS\t{}", self.renders)?;
+            return Ok(());
+        }
+    }
+
+    impl FromStr for SessionState {
+        type Err = TemplaterError;
+
+        fn from_str(text: &str) -> Result<Self, Self::Err> {
+            let mut state = SessionState::default();
+            for (i, line) in text.lines().enumerate() {
+                let error = |message: String| TemplaterError::Parse {
+                    file: INLINE_SOURCE.to_string(),
+                    line: i + 1,
+                    message,
+                };
+                match line.split('\t').collect::<Vec<&str>>().as_slice() {
+                    [] | [""] => {}
+                    ["USED", query, id] => {
+                        let query = unescape_key(query);
+                        let id = Uuid::parse_str(id)
+                            .map_err(|_| error(format!("invalid id '{}'", id)))?;
+                        state.used.entry(query).or_default().insert(id);
+                    }
+                    ["RENDERED", ..] => {
+                        state
+                            .rendered
+                            .insert(line["RENDERED\t".len()..].to_string());
+                    }
+                    ["RECENT", id, render] => {
+                        let id = Uuid::parse_str(id)
+                            .map_err(|_| error(format!("invalid id '{}'", id)))?;
+                        let render = render
+                            .parse()
+                            .map_err(|_| error(format!("invalid render '{}'", render)))?;
+                        state.recent.insert(id, render);
+                    }
+                    ["RENDERS", count] => {
+                        state.renders = count
+                            .parse()
+                            .map_err(|_| error(format!("invalid render count '{}'", count)))?;
+                    }
+                    _ => return Err(error(format!("unexpected session line '{}'", line))),
+                }
+            }
+            return Ok(state);
+        }
+    }
+
+    #[test]
+    fn test_session_words_do_not_repeat() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Gold), TAG(Metal)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+        ]);
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut session = GenerationSession::new(&dict);
+        let metal = Query::noun().all_of(["Metal"]);
+        let mut drawn: BTreeSet<String> = BTreeSet::new();
+        for _ in 0..3 {
+            drawn.insert(
+                session
+                    .try_get_random_word(&metal, &mut rng)
+                    .unwrap()
+                    .base
+                    .clone(),
+            );
+        }
+        assert!(drawn.len().eq(&3));
+        assert!(session.remaining(&metal).eq(&0));
+        let exhausted = session.try_get_random_word(&metal, &mut rng).unwrap_err();
+        assert!(exhausted
+            .to_string()
+            .eq("every match of NOUN[Metal] has been used"));
+
+        // other queries keep their own history
+        assert!(session
+            .get_random_word(Query::any().all_of(["Metal"]), &mut rng)
+            .is_some());
+        let missing = session.try_get_random_word(Query::noun().all_of(["Wood"]), &mut rng);
+        assert!(matches!(missing, Err(TemplaterError::EmptySlot(_))));
+
+        session.reset_query(&metal);
+        assert!(session.remaining(&metal).eq(&3));
+    }
+
+    #[test]
+    fn test_session_renders_do_not_repeat() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Gold), TAG(Metal)".to_string(),
+            "ADJECTIVE(Red), TAG(Colour)".to_string(),
+            "ADJECTIVE(Blue), TAG(Colour)".to_string(),
+            "TEMPLATE(ADJECTIVE[Colour] NOUN[Metal] Inn), TAG(Inn)".to_string(),
+        ]);
+        let id = *dict.templates.keys().next().unwrap();
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut session = GenerationSession::new(&dict);
+        let mut rendered: BTreeSet<String> = BTreeSet::new();
+        for _ in 0..4 {
+            rendered.insert(session.try_render_template(&id, &mut rng).unwrap());
+        }
+        assert!(rendered.len().eq(&4));
+        let exhausted = session.try_render_template(&id, &mut rng);
+        assert!(matches!(
+            exhausted,
+            Err(TemplaterError::ExhaustedTemplate(_))
+        ));
+
+        // without replacement in the slots the two metals run out after two renders
+        session.reset();
+        session.unique_slot_words = true;
+        assert!(session.render_template(&id, &mut rng).is_some());
+        assert!(session.render_template(&id, &mut rng).is_some());
+        let exhausted = session.try_render_template(&id, &mut rng);
+        assert!(matches!(exhausted, Err(TemplaterError::Exhausted(_))));
+    }
+
+    #[test]
+    fn test_recent_window() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let mut lines: Vec<String> = (0..5)
+            .map(|i| format!("NOUN(Name{}), TAG(LastName)", i))
+            .collect();
+        lines.extend((0..10).map(|i| format!("ADJECTIVE(Colour{}), TAG(Colour)", i)));
+        lines.push("TEMPLATE(ADJECTIVE[Colour] NOUN[LastName]), TAG(Sign)".to_string());
+        let dict = build_dictionary(lines);
+        let id = *dict.templates.keys().next().unwrap();
+        let last_name = Query::noun().all_of(["LastName"]);
+        let mut rng = StdRng::seed_from_u64(8);
+
+        // excluded for four renders, so any five in a row are distinct, names or slots
+        let mut session = GenerationSession::new(&dict);
+        session.unique_words = false;
+        session.unique_renders = false;
+        session.suppress_recent(
+            "LastName".parse::<Query>().unwrap(),
+            RecentWindow {
+                renders: 4,
+                weight: 0.0,
+            },
+        );
+        let mut names: Vec<String> = Vec::new();
+        for i in 0..40 {
+            if i % 2 == 0 {
+                let word = session.try_get_random_word(&last_name, &mut rng).unwrap();
+                names.push(word.base.clone());
+            } else {
+                let text = session.try_render_template(&id, &mut rng).unwrap();
+                names.push(text.split(' ').nth(1).unwrap().to_string());
+            }
+        }
+        assert!(names
+            .windows(5)
+            .all(|w| w.iter().collect::<BTreeSet<&String>>().len() == 5));
+        // the last four renders, at most a colour and a name each
+        assert!(session.state().recent.len() <= 8);
+
+        // a window wider than the matches lets words back in rather than failing
+        session.reset();
+        session.suppress_recent(
+            &last_name,
+            RecentWindow {
+                renders: 10,
+                weight: 0.0,
+            },
+        );
+        for _ in 0..20 {
+            assert!(session.get_random_word(&last_name, &mut rng).is_some());
+        }
+
+        // down weighted: the last name is picked a quarter as often as each of the others
+        let mut session = GenerationSession::new(&dict);
+        session.unique_words = false;
+        session.suppress_recent(
+            &last_name,
+            RecentWindow {
+                renders: 1,
+                weight: 0.25,
+            },
+        );
+        let mut repeats = 0;
+        let mut last = String::new();
+        for _ in 0..4000 {
+            let name = session.try_get_random_word(&last_name, &mut rng).unwrap();
+            if name.base.eq(&last) {
+                repeats += 1;
+            }
+            last = name.base.clone();
+        }
+        // 0.25 / 4.25 of the draws
+        assert!((170..300).contains(&repeats));
+    }
+
+    #[test]
+    fn test_session_state_round_trip() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Gold), TAG(Metal)".to_string(),
+            "TEMPLATE(The NOUN[Metal] Inn), TAG(Inn)".to_string(),
+        ]);
+        let id = *dict.templates.keys().next().unwrap();
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut session = GenerationSession::new(&dict);
+        let first = session.try_get_random_word(Query::noun().all_of(["Metal"]), &mut rng);
+        let first = first.unwrap().base.clone();
+        session.try_render_template(&id, &mut rng).unwrap();
+
+        let saved = session.state().to_string();
+        let state: SessionState = saved.parse().unwrap();
+        assert!(state.eq(session.state()));
+        let mut resumed = GenerationSession::resume(&dict, state);
+        let second = resumed.try_get_random_word(Query::noun().all_of(["Metal"]), &mut rng);
+        assert!(!second.unwrap().base.eq(&first));
+        assert!(resumed.render_template(&id, &mut rng).is_some());
+        assert!(resumed.render_template(&id, &mut rng).is_none());
+
+        assert!("USED\tNOUN[Metal]\tnot-an-id"
+            .parse::<SessionState>()
+            .is_err());
+        assert!("SKIPPED\tSteel".parse::<SessionState>().is_err());
+    }
+
+    #[test]
+    fn test_session_state_escaped_tags() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "NOUN(Salt), TAG(Salt\\,Pepper\\(Ground\\)\\#1\\\\Co)".to_string(),
+            "NOUN(Pepper), TAG(Salt\\,Pepper\\(Ground\\)\\#1\\\\Co)".to_string(),
+        ]);
+        let query = Query::noun().all_of(["Salt,Pepper(Ground)#1\\Co"]);
+        let mut rng = StdRng::seed_from_u64(4);
+        let mut session = GenerationSession::new(&dict);
+        assert!(session.remaining(&query).eq(&2));
+        session.try_get_random_word(&query, &mut rng).unwrap();
+
+        let saved = session.state().to_string();
+        assert!(saved.starts_with("USED\tNOUN[Salt,Pepper(Ground)#1\\\\Co]\t"));
+        let state: SessionState = saved.parse().unwrap();
+        assert!(state.eq(session.state()));
+        let resumed = GenerationSession::resume(&dict, state);
+        assert!(resumed.remaining(&query).eq(&1));
+    }
+}
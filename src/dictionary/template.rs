@@ -1,57 +1,233 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
 pub mod template {
-    use std::{collections::HashSet, str::FromStr};
+    use std::collections::{BTreeMap, BTreeSet};
 
-    use regex::Regex;
+    use rand::Rng;
     use titlecase::titlecase;
     use uuid::Uuid;
 
-    use crate::dictionary::{
-        dictionary::{Dictionary, SearchPattern},
-        word::word::{get_word_tags, WordType},
+    use crate::{
+        dictionary::{
+            dictionary::{content_id, Dictionary, INLINE_SOURCE},
+            parser::parser::{parse_line, Entry, ParsedLine},
+            query::query::{Query, QueryExpr},
+            word::word::{line_tags, line_weight, Word, WordRelationType},
+        },
+        error::error::TemplaterError,
     };
 
-    static TEMPLATE_WRAPPER: &str = "TEMPLATE";
+    pub static TEMPLATE_WRAPPER: &str = "TEMPLATE";
+
+    // how many TEMPLATE slots deep a render may go before it is abandoned
+    pub static DEFAULT_TEMPLATE_DEPTH: usize = 8;
 
     #[derive(PartialEq, Debug, Clone)]
-    pub struct TemplateElement {
-        pub text: Option<String>,
-        pub template: Option<SearchPattern>,
+    pub enum TemplateElement {
+        Text(String),
+        Slot(Query),
+        // TEMPLATE[expr], filled by rendering a template the expression matches
+        Nested(QueryExpr),
+        // NAME[name], filled by rendering the template with that NAME
+        Named(String),
+        // SLOT@name, a word slot whose word is kept for later @name elements
+        Bound(Query, String),
+        // @name, the word bound earlier in the same template
+        Variable(String),
+        // @name.ADJECTIVE or @name.NOUN, a form related to the bound word
+        Related(String, WordRelationType),
+        // [elements]?N%, rendered N percent of the time and left out otherwise
+        Optional(Vec<TemplateElement>, u8),
     }
 
     impl Dictionary {
-        pub fn render_template(self: &Self, template_id: &Uuid) -> Option<String> {
-            let t = self.templates.get(&template_id);
-            if t.is_some() {
-                let template = t.unwrap();
-                let components: Vec<String> = template
-                    .template
-                    .iter()
-                    .map(|c| {
-                        if c.template.is_some() {
-                            self.get_random_word((
-                                c.template.clone().unwrap().0,
-                                c.template.clone().unwrap().1,
-                            ))
-                            .unwrap()
-                            .base
-                            .clone()
-                        } else {
-                            c.text.clone().unwrap()
-                        }
-                    })
-                    .collect();
-                return Some(components.join("").replace(" '", "'"));
+        pub fn try_render_template<R: Rng + ?Sized>(
+            self: &Self,
+            template_id: &Uuid,
+            rng: &mut R,
+        ) -> Result<String, TemplaterError> {
+            return self.try_render_template_with(template_id, rng, |query, forms, rng| {
+                return self
+                    .get_random_word_with_forms(query, forms, &BTreeSet::new(), rng)
+                    .ok_or_else(|| {
+                        let empty = TemplaterError::EmptySlot(query.clone());
+                        self.unfillable(query, forms).unwrap_or(empty)
+                    });
+            });
+        }
+
+        // renders with each word slot filled by `pick`, so callers can control how words
+        // are drawn. `pick` is also given the related forms the word must have, those its
+        // binding is used for as '@name.ADJECTIVE' or '@name.NOUN'.
+        pub fn try_render_template_with<'a, R, F>(
+            self: &'a Self,
+            template_id: &Uuid,
+            rng: &mut R,
+            mut pick: F,
+        ) -> Result<String, TemplaterError>
+        where
+            R: Rng + ?Sized,
+            F: FnMut(&Query, &[WordRelationType], &mut R) -> Result<&'a Word, TemplaterError>,
+        {
+            let text = self.render_nested(template_id, 0, rng, &mut pick)?;
+            return Ok(text.replace(" '", "'"));
+        }
+
+        fn render_nested<'a, R, F>(
+            self: &'a Self,
+            template_id: &Uuid,
+            depth: usize,
+            rng: &mut R,
+            pick: &mut F,
+        ) -> Result<String, TemplaterError>
+        where
+            R: Rng + ?Sized,
+            F: FnMut(&Query, &[WordRelationType], &mut R) -> Result<&'a Word, TemplaterError>,
+        {
+            if depth > self.max_template_depth {
+                return Err(TemplaterError::NestingTooDeep {
+                    template: *template_id,
+                    limit: self.max_template_depth,
+                });
             }
-            return None;
+            let template = self
+                .templates
+                .get(template_id)
+                .ok_or(TemplaterError::UnknownTemplate(*template_id))?;
+            // bindings are local to each template, nested templates start with none
+            let mut bound: BTreeMap<&str, &'a Word> = BTreeMap::new();
+            return self.render_elements(&template.template, &mut bound, depth, rng, pick);
         }
 
-        pub fn render_template_as_title(self: &Self, template_id: &Uuid) -> Option<String> {
-            let o = self.render_template(template_id);
-            if o.is_none() {
-                return None;
-            } else {
-                return Some(titlecase(o.unwrap().to_ascii_lowercase().as_str()));
+        fn render_elements<'a, R, F>(
+            self: &'a Self,
+            elements: &'a [TemplateElement],
+            bound: &mut BTreeMap<&'a str, &'a Word>,
+            depth: usize,
+            rng: &mut R,
+            pick: &mut F,
+        ) -> Result<String, TemplaterError>
+        where
+            R: Rng + ?Sized,
+            F: FnMut(&Query, &[WordRelationType], &mut R) -> Result<&'a Word, TemplaterError>,
+        {
+            let mut output = String::new();
+            // set when a segment is left out, until some text follows it
+            let mut omitted = false;
+            for (i, element) in elements.iter().enumerate() {
+                let component = match element {
+                    TemplateElement::Text(text) => text.clone(),
+                    TemplateElement::Slot(query) => pick(query, &[], rng)?.base.clone(),
+                    TemplateElement::Bound(query, name) => {
+                        let word = pick(query, &forms_used(&elements[i + 1..], name), rng)?;
+                        bound.insert(name, word);
+                        word.base.clone()
+                    }
+                    TemplateElement::Variable(name) => {
+                        let word = bound
+                            .get(name.as_str())
+                            .ok_or(TemplaterError::UnboundVariable(name.clone()))?;
+                        word.base.clone()
+                    }
+                    TemplateElement::Related(name, relation) => {
+                        let word = bound
+                            .get(name.as_str())
+                            .ok_or(TemplaterError::UnboundVariable(name.clone()))?;
+                        let related = self
+                            .related_words(&word.id, relation.clone())
+                            .into_iter()
+                            .next()
+                            .ok_or(TemplaterError::NoRelatedWord {
+                                word: word.base.clone(),
+                                relation: relation.clone(),
+                            })?;
+                        related.base.clone()
+                    }
+                    TemplateElement::Nested(expr) => {
+                        let nested = self
+                            .get_random_template_matching(expr, rng)
+                            .ok_or(TemplaterError::EmptySlot(Query::from(expr.clone())))?;
+                        self.render_nested(&nested.id, depth + 1, rng, pick)?
+                    }
+                    TemplateElement::Named(name) => {
+                        let named = self.try_template_named(name)?;
+                        self.render_nested(&named.id, depth + 1, rng, pick)?
+                    }
+                    TemplateElement::Optional(segment, chance) => {
+                        if !rng.gen_ratio(u32::from(*chance), 100) {
+                            omitted = true;
+                            continue;
+                        }
+                        // render on a copy so the segment's draws don't leak into the rest
+                        let mut scoped = bound.clone();
+                        self.render_elements(segment, &mut scoped, depth, rng, pick)?
+                    }
+                };
+                push_component(&mut output, &component, &mut omitted);
             }
+            if omitted {
+                output.truncate(output.trim_end().len());
+            }
+            return Ok(output);
+        }
+
+        pub fn template_named(self: &Self, name: &str) -> Option<&Template> {
+            return self
+                .index
+                .template_names
+                .get(name)
+                .and_then(|id| self.templates.get(id));
+        }
+
+        fn try_template_named(self: &Self, name: &str) -> Result<&Template, TemplaterError> {
+            return self
+                .template_named(name)
+                .ok_or(TemplaterError::UnknownName(name.to_string()));
+        }
+
+        pub fn try_render_named<R: Rng + ?Sized>(
+            self: &Self,
+            name: &str,
+            rng: &mut R,
+        ) -> Result<String, TemplaterError> {
+            let template = self.try_template_named(name)?;
+            return self.try_render_template(&template.id, rng);
+        }
+
+        pub fn render_named<R: Rng + ?Sized>(
+            self: &Self,
+            name: &str,
+            rng: &mut R,
+        ) -> Option<String> {
+            return self.try_render_named(name, rng).ok();
+        }
+
+        pub fn render_template<R: Rng + ?Sized>(
+            self: &Self,
+            template_id: &Uuid,
+            rng: &mut R,
+        ) -> Option<String> {
+            return self.try_render_template(template_id, rng).ok();
+        }
+
+        pub fn try_render_template_as_title<R: Rng + ?Sized>(
+            self: &Self,
+            template_id: &Uuid,
+            rng: &mut R,
+        ) -> Result<String, TemplaterError> {
+            let o = self.try_render_template(template_id, rng)?;
+            return Ok(titlecase(o.to_lowercase().as_str()));
+        }
+
+        pub fn render_template_as_title<R: Rng + ?Sized>(
+            self: &Self,
+            template_id: &Uuid,
+            rng: &mut R,
+        ) -> Option<String> {
+            return self.try_render_template_as_title(template_id, rng).ok();
         }
     }
 
@@ -59,87 +235,83 @@ pub mod template {
     pub struct Template {
         pub id: Uuid,
         pub template: Vec<TemplateElement>,
-        pub tags: HashSet<String>,
+        pub tags: BTreeSet<String>,
+        pub weight: f64,
+        pub name: Option<String>,
     }
 
-    pub fn parse_template(line: &str) -> Option<Template> {
-        if !line.contains(TEMPLATE_WRAPPER) {
-            return None;
-        }
-        let and_groups_pattern =
-            Regex::new(r"(ADJECTIVE|NOUN)\[((?:\[(?:[a-zA-Z', ]+)+\])+)\]").unwrap();
-        let subset_pattern = Regex::new(&format!(
-            r"{}|(?:[a-zA-Z\s']?[a-z'\s]+)",
-            and_groups_pattern.as_str()
-        ))
-        .unwrap();
-        let search_pattern = Regex::new(&format!(
-            r"TEMPLATE\((?:(?:(?:{}\s?)+)+|(?:[A-Za-z' ]+))+\)",
-            subset_pattern.as_str()
-        ))
-        .unwrap();
-        let search_result = search_pattern.find(line);
-
-        let mut output = Template {
-            id: Uuid::new_v4(),
-            template: Vec::new(),
-            tags: HashSet::new(),
-        };
-        if search_result.is_none() {
-            return None;
+    // punctuation that hangs off the text before it, so "A [B]?0%\, C" reads "A, C"
+    static CLOSING_PUNCTUATION: &str = ",.;:!?)";
+
+    // appends a rendered component, dropping the whitespace a left out segment would
+    // otherwise double up or leave at the start
+    fn push_component(output: &mut String, component: &str, omitted: &mut bool) {
+        let mut component = component;
+        if *omitted && (output.is_empty() || output.ends_with(char::is_whitespace)) {
+            component = component.trim_start();
         }
-        for subset in subset_pattern
-            .find_iter(search_result.unwrap().as_str())
-            .map(|m| m.as_str())
-        {
-            let mut pattern: SearchPattern = (WordType::Noun, Vec::new());
-            let and_groups = and_groups_pattern.captures(subset.trim());
-
-            if and_groups.is_some() {
-                let group: Vec<Option<regex::Match>> = and_groups.unwrap().iter().collect();
-                let mut options = group.first().unwrap().unwrap().as_str().to_string();
-
-                if Regex::from_str(r"^ADJECTIVE.*")
-                    .unwrap()
-                    .is_match(&options.trim())
-                {
-                    pattern.0 = WordType::Adjective;
-                }
-                options = options.replace("ADJECTIVE", "");
-                options = options.replace("NOUN", "");
-                let or_groups: Vec<String> = options
-                    .split("]")
-                    .into_iter()
-                    .filter(|i| i.len() > 1)
-                    .map(|i| i.replace("[", ""))
-                    .collect();
-
-                for or_group in or_groups {
-                    let and_elements: Vec<String> = or_group
-                        .split(",")
-                        .into_iter()
-                        .map(|i| i.trim())
-                        .map(|i| i.to_string())
-                        .collect();
-                    pattern.1.push(and_elements);
+        if *omitted && component.starts_with(|c| CLOSING_PUNCTUATION.contains(c)) {
+            output.truncate(output.trim_end().len());
+        }
+        if !component.is_empty() {
+            *omitted = false;
+        }
+        output.push_str(component);
+    }
+
+    // the related forms asked of a binding by the elements after it, segments included,
+    // since a segment cannot bind the same name again
+    fn forms_used(elements: &[TemplateElement], name: &str) -> Vec<WordRelationType> {
+        let mut output: Vec<WordRelationType> = Vec::new();
+        for element in elements {
+            let forms = match element {
+                TemplateElement::Related(used, relation) if used.eq(name) => vec![relation.clone()],
+                TemplateElement::Optional(segment, _) => forms_used(segment, name),
+                _ => Vec::new(),
+            };
+            for form in forms {
+                if !output.contains(&form) {
+                    output.push(form);
                 }
+            }
+        }
+        return output;
+    }
 
-                output.template.push(TemplateElement {
-                    text: None,
-                    template: Some(pattern),
-                });
-            } else {
-                if !subset.eq(TEMPLATE_WRAPPER) {
-                    output.template.push(TemplateElement {
-                        text: Some(subset.to_string()),
-                        template: None,
-                    });
+    impl Template {
+        // every element, including those inside optional segments
+        pub fn elements(self: &Self) -> Vec<&TemplateElement> {
+            let mut output: Vec<&TemplateElement> = Vec::new();
+            let mut queue: Vec<&TemplateElement> = self.template.iter().rev().collect();
+            while let Some(element) = queue.pop() {
+                if let TemplateElement::Optional(segment, _) = element {
+                    queue.extend(segment.iter().rev());
                 }
+                output.push(element);
             }
+            return output;
         }
+    }
+
+    pub fn parse_template(line: &str) -> Option<Template> {
+        return template_from_line(&parse_line(INLINE_SOURCE, 1, line));
+    }
 
-        output.tags = HashSet::from_iter(get_word_tags(line));
-        return Some(output);
+    pub fn template_from_line(parsed: &ParsedLine) -> Option<Template> {
+        let elements = parsed.entries.iter().find_map(|(entry, _)| match entry {
+            Entry::Template(elements) => Some(elements.clone()),
+            _ => None,
+        })?;
+        return Some(Template {
+            id: content_id(TEMPLATE_WRAPPER, &parsed.key),
+            template: elements,
+            tags: line_tags(parsed),
+            weight: line_weight(parsed),
+            name: parsed.entries.iter().find_map(|(entry, _)| match entry {
+                Entry::Name(name) => Some(name.clone()),
+                _ => None,
+            }),
+        });
     }
 
     // example template string
@@ -149,7 +321,8 @@ pub mod template {
     fn test_parse_template() {
         let test_string = "TEMPLATE(ADJECTIVE[[Metal, Wood]] NOUN[[Mammal]] Tavern), TAG(Institution), TAG(Restaurant)";
         let template = parse_template(test_string).unwrap();
-        assert!(template.template.len().eq(&5));
+        assert!(template.template.len().eq(&4));
+        assert!(template.template[3].eq(&TemplateElement::Text(" Tavern".to_string())));
         assert!(template.tags.len().eq(&2));
     }
 
@@ -173,7 +346,7 @@ pub mod template {
         let template = template_keys.first().unwrap();
 
         assert!(dict
-            .render_template(template)
+            .render_template(template, &mut rand::thread_rng())
             .unwrap()
             .eq("Blue Steel Bull's Pub"));
     }
@@ -192,25 +365,298 @@ pub mod template {
         let template = template_keys.first().unwrap();
 
         assert!(dict
-            .render_template_as_title(template)
+            .render_template_as_title(template, &mut rand::thread_rng())
             .unwrap()
             .eq("Blueford"));
     }
 
     #[test]
     fn test_template_correctness() {
-        use crate::dictionary::dictionary::build_dictionary;
+        use crate::dictionary::{dictionary::build_dictionary, word::word::WordType};
         let dict = build_dictionary(vec![
             "TEMPLATE(ADJECTIVE[[Large, Medium][Mammal, Bird]])".to_string()
         ]);
         let templates: Vec<&Template> = dict.templates.values().collect();
         let template = templates.first().unwrap();
         let element = template.template.first().unwrap().clone();
-        let element_template = element.template.unwrap();
-        assert!(element_template.0.eq(&WordType::Adjective));
-        assert!(element_template.1.eq(&vec![
-            vec!["Large".to_string(), "Medium".to_string()],
-            vec!["Mammal".to_string(), "Bird".to_string()]
-        ]));
+        let element_template = match element {
+            TemplateElement::Slot(query) => query,
+            _ => panic!("expected a slot"),
+        };
+        assert!(element_template.word_type.eq(&Some(WordType::Adjective)));
+        assert!(element_template
+            .to_string()
+            .eq("ADJECTIVE[(Large | Medium) & (Mammal | Bird)]"));
+    }
+
+    #[test]
+    fn test_seeded_template_render() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "TEMPLATE(ADJECTIVE[[Colour]] NOUN[[Metal]] Tavern)".to_string(),
+            "ADJECTIVE(Blue), TAG(Colour)".to_string(),
+            "ADJECTIVE(Red), TAG(Colour)".to_string(),
+            "ADJECTIVE(Green), TAG(Colour)".to_string(),
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+            "NOUN(Gold), TAG(Metal)".to_string(),
+        ]);
+        let template = *Vec::from_iter(dict.templates.keys()).first().unwrap();
+        let render = |seed: u64| -> Vec<String> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20)
+                .map(|_| dict.render_template(template, &mut rng).unwrap())
+                .collect()
+        };
+        assert!(render(42).eq(&render(42)));
+    }
+
+    #[test]
+    fn test_try_render_template() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "TEMPLATE(ADJECTIVE[[Colour]] NOUN[[Mammal]] Inn)".to_string(),
+            "ADJECTIVE(Blue), TAG(Colour)".to_string(),
+        ]);
+        let mut rng = rand::thread_rng();
+        let template = *Vec::from_iter(dict.templates.keys()).first().unwrap();
+        assert!(matches!(
+            dict.try_render_template(template, &mut rng),
+            Err(TemplaterError::EmptySlot(_))
+        ));
+        assert!(dict.render_template(template, &mut rng).is_none());
+        assert!(matches!(
+            dict.try_render_template(&Uuid::nil(), &mut rng),
+            Err(TemplaterError::UnknownTemplate(_))
+        ));
+    }
+
+    #[test]
+    fn test_nested_template_render() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let mut dict = build_dictionary(vec![
+            "TEMPLATE(The NOUN[[Animal]]'s TEMPLATE[[Drink]]), TAG(Tavern)".to_string(),
+            "TEMPLATE(ADJECTIVE[Colour] NOUN[Drink]), TAG(Drink)".to_string(),
+            "NOUN(Bull), TAG(Animal)".to_string(),
+            "NOUN(Ale), TAG(Drink)".to_string(),
+            "ADJECTIVE(Brown), TAG(Colour)".to_string(),
+            "TEMPLATE(TEMPLATE[Loop] again), TAG(Loop)".to_string(),
+        ]);
+        let mut rng = StdRng::seed_from_u64(2);
+        let tavern = dict
+            .get_random_template(Query::any().all_of(["Tavern"]), &mut rng)
+            .unwrap();
+        assert!(
+            matches!(&tavern.template[3], TemplateElement::Nested(expr) if expr.to_string().eq("Drink"))
+        );
+        let id = tavern.id;
+        assert!(dict
+            .try_render_template(&id, &mut rng)
+            .unwrap()
+            .eq("The Bull's Brown Ale"));
+
+        dict.max_template_depth = 0;
+        assert!(matches!(
+            dict.try_render_template(&id, &mut rng),
+            Err(TemplaterError::NestingTooDeep { limit: 0, .. })
+        ));
+        dict.max_template_depth = 3;
+        let looped = dict
+            .get_random_template(Query::any().all_of(["Loop"]), &mut rng)
+            .unwrap()
+            .id;
+        assert!(dict
+            .try_render_template(&looped, &mut rng)
+            .unwrap_err()
+            .to_string()
+            .ends_with("is nested more than 3 TEMPLATE slots deep"));
+    }
+
+    #[test]
+    fn test_named_templates() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "TEMPLATE(The NOUN[Animal] NAME[Sign]), NAME(TavernFull), TAG(Tavern)".to_string(),
+            "TEMPLATE(and NOUN[Drink]), NAME(Sign)".to_string(),
+            "TEMPLATE(NAME[Missing] Road)".to_string(),
+            "NOUN(Bull), TAG(Animal)".to_string(),
+            "NOUN(Ale), TAG(Drink)".to_string(),
+        ]);
+        let mut rng = rand::thread_rng();
+        let full = dict.template_named("TavernFull").unwrap();
+        assert!(full.name.eq(&Some("TavernFull".to_string())));
+        assert!(full.template[3].eq(&TemplateElement::Named("Sign".to_string())));
+        assert!(dict
+            .render_named("TavernFull", &mut rng)
+            .unwrap()
+            .eq("The Bull and Ale"));
+        assert!(matches!(
+            dict.try_render_named("Tavern", &mut rng),
+            Err(TemplaterError::UnknownName(_))
+        ));
+        let road = dict.templates.values().find(|t| t.name.is_none()).unwrap();
+        assert!(dict
+            .try_render_template(&road.id, &mut rng)
+            .unwrap_err()
+            .to_string()
+            .eq("no template is named 'Missing'"));
+    }
+
+    #[test]
+    fn test_variable_render() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "TEMPLATE(The NOUN[Metal]@m NOUN[Animal] - finest @m in town)".to_string(),
+            "NOUN(Steel), TAG(Metal)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+            "NOUN(Gold), TAG(Metal)".to_string(),
+            "NOUN(Bull), TAG(Animal)".to_string(),
+        ]);
+        let id = *dict.templates.keys().next().unwrap();
+        let mut rng = StdRng::seed_from_u64(3);
+        for _ in 0..30 {
+            let text = dict.try_render_template(&id, &mut rng).unwrap();
+            let words: Vec<&str> = text.split(' ').collect();
+            assert!(words[1].eq(words[5]));
+        }
+
+        let unbound = Template {
+            id: Uuid::nil(),
+            template: vec![TemplateElement::Variable("m".to_string())],
+            tags: BTreeSet::new(),
+            weight: 1.0,
+            name: None,
+        };
+        let mut dict = dict;
+        dict.templates.insert(unbound.id, unbound);
+        assert!(matches!(
+            dict.try_render_template(&Uuid::nil(), &mut rng),
+            Err(TemplaterError::UnboundVariable(_))
+        ));
+    }
+
+    #[test]
+    fn test_related_render() {
+        use crate::dictionary::{
+            dictionary::build_dictionary, session::session::GenerationSession,
+        };
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "TEMPLATE(NOUN[Metal]@m Works: @m.ADJECTIVE goods), TAG(Forge)".to_string(),
+            "TEMPLATE(The ADJECTIVE[Metal]@m @m.NOUN), TAG(Sign)".to_string(),
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal)".to_string(),
+            "NOUN(Gold), ADJECTIVE(Golden), TAG(Metal)".to_string(),
+        ]);
+        let mut rng = StdRng::seed_from_u64(5);
+        let forge = dict
+            .get_random_template(Query::any().all_of(["Forge"]), &mut rng)
+            .unwrap()
+            .id;
+        let sign = dict
+            .get_random_template(Query::any().all_of(["Sign"]), &mut rng)
+            .unwrap()
+            .id;
+        for _ in 0..20 {
+            let text = dict.try_render_template(&forge, &mut rng).unwrap();
+            assert!(text.eq("Steel Works: Steely goods") || text.eq("Gold Works: Golden goods"));
+            let text = dict.try_render_template(&sign, &mut rng).unwrap();
+            assert!(text.eq("The Steely Steel") || text.eq("The Golden Gold"));
+        }
+
+        // the bound slot only draws words that have the form, here or in a segment
+        let mixed = build_dictionary(vec![
+            "TEMPLATE(NOUN[Metal]@m Works[: @m.ADJECTIVE goods]?100%)".to_string(),
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+            "NOUN(Iron), TAG(Metal)".to_string(),
+            "NOUN(Lead), TAG(Metal)".to_string(),
+        ]);
+        let forge = *mixed.templates.keys().next().unwrap();
+        for _ in 0..20 {
+            let text = mixed.try_render_template(&forge, &mut rng).unwrap();
+            assert!(text.eq("Steel Works: Steely goods"));
+        }
+        let mut session = GenerationSession::new(&mixed);
+        session.unique_renders = false;
+        session.unique_slot_words = true;
+        assert!(session.render_template(&forge, &mut rng).is_some());
+        assert!(matches!(
+            session.try_render_template(&forge, &mut rng),
+            Err(TemplaterError::Exhausted(_))
+        ));
+
+        let tin = build_dictionary(vec![
+            "TEMPLATE(NOUN[Metal]@m Works: @m.ADJECTIVE goods)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+        ]);
+        let forge = *tin.templates.keys().next().unwrap();
+        assert!(tin
+            .try_render_template(&forge, &mut rng)
+            .unwrap_err()
+            .to_string()
+            .eq("nothing matching NOUN[Metal] has a related ADJECTIVE"));
+    }
+
+    #[test]
+    fn test_optional_render() {
+        use crate::dictionary::dictionary::build_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_dictionary(vec![
+            "TEMPLATE(The [ADJECTIVE[[Colour]] ]?50%NOUN[[Animal]]), TAG(Inn)".to_string(),
+            "TEMPLATE([Old]?50% NOUN[[Animal]] [Inn]?50%), TAG(Sign)".to_string(),
+            "ADJECTIVE(Blue), TAG(Colour)".to_string(),
+            "NOUN(Bull), TAG(Animal)".to_string(),
+        ]);
+        let mut rng = StdRng::seed_from_u64(3);
+        let inn = dict
+            .get_random_template(Query::any().all_of(["Inn"]), &mut rng)
+            .unwrap()
+            .id;
+        let sign = dict
+            .get_random_template(Query::any().all_of(["Sign"]), &mut rng)
+            .unwrap()
+            .id;
+        let mut seen: BTreeSet<String> = BTreeSet::new();
+        for _ in 0..100 {
+            seen.insert(dict.try_render_template(&inn, &mut rng).unwrap());
+            seen.insert(dict.try_render_template(&sign, &mut rng).unwrap());
+        }
+        let expected = [
+            "The Blue Bull",
+            "The Bull",
+            "Old Bull Inn",
+            "Old Bull",
+            "Bull Inn",
+            "Bull",
+        ];
+        assert!(seen.eq(&expected.iter().map(|s| s.to_string()).collect()));
+
+        let dict = build_dictionary(vec![
+            "TEMPLATE(NOUN[[Animal]] [Inn]?0%\\, ADJECTIVE[[Colour]] [Sign]?0%.)".to_string(),
+            "ADJECTIVE(Blue), TAG(Colour)".to_string(),
+            "NOUN(Bull), TAG(Animal)".to_string(),
+        ]);
+        let id = *dict.templates.keys().next().unwrap();
+        assert!(dict
+            .try_render_template(&id, &mut rng)
+            .unwrap()
+            .eq("Bull, Blue."));
+    }
+
+    #[test]
+    fn test_unicode_title_render() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "TEMPLATE(NOUN[[Surname]] & Søn\\, Öl-Händler)".to_string(),
+            "NOUN(ÖZTÜRK), TAG(Surname)".to_string(),
+        ]);
+        let template = *Vec::from_iter(dict.templates.keys()).first().unwrap();
+        assert!(dict
+            .render_template_as_title(template, &mut rand::thread_rng())
+            .unwrap()
+            .eq("Öztürk & Søn, Öl-Händler"));
     }
 }
@@ -1,100 +1,172 @@
+#[allow(
+    clippy::module_inception,
+    clippy::needless_return,
+    clippy::needless_arbitrary_self_type
+)]
 pub mod word {
-    use std::collections::HashSet;
+    use std::collections::BTreeSet;
 
-    use regex::Regex;
     use uuid::Uuid;
 
-    use crate::dictionary::dictionary::{ADJECTIVE_WRAPPER, NOUN_WRAPPER, TAG_WRAPPER};
+    use crate::dictionary::{
+        dictionary::{content_id, Dictionary, ADJECTIVE_WRAPPER, INLINE_SOURCE, NOUN_WRAPPER},
+        parser::parser::{parse_line, Entry, ParsedLine},
+        rarity::rarity::Rarity,
+    };
 
-    #[derive(PartialEq, Debug, Clone, Hash, Eq)]
+    #[derive(PartialEq, Debug, Clone, Hash, Eq, PartialOrd, Ord)]
     pub enum WordType {
         Noun,
         Adjective,
     }
 
-    #[derive(PartialEq, Debug, Clone, Hash, Eq)]
+    #[derive(PartialEq, Debug, Clone, Hash, Eq, PartialOrd, Ord)]
     pub enum WordRelationType {
         BaseNoun,
         Adjective,
     }
 
+    impl WordRelationType {
+        // the wrapper naming the related form, as written after '@name.' in templates
+        pub fn keyword(self: &Self) -> &'static str {
+            return match self {
+                WordRelationType::BaseNoun => NOUN_WRAPPER,
+                WordRelationType::Adjective => ADJECTIVE_WRAPPER,
+            };
+        }
+
+        pub fn from_keyword(keyword: &str) -> Option<WordRelationType> {
+            return [WordRelationType::BaseNoun, WordRelationType::Adjective]
+                .into_iter()
+                .find(|relation| relation.keyword().eq(keyword));
+        }
+    }
+
+    impl Dictionary {
+        // the forms of a word declared on the same line, such as steely for steel
+        pub fn related_words(self: &Self, id: &Uuid, relation: WordRelationType) -> Vec<&Word> {
+            return self
+                .words
+                .get(id)
+                .map(|word| {
+                    word.related
+                        .iter()
+                        .filter(|(kind, _)| kind.eq(&relation))
+                        .filter_map(|(_, related)| self.words.get(related))
+                        .collect()
+                })
+                .unwrap_or_default();
+        }
+    }
+
     #[derive(PartialEq, Debug, Clone)]
     pub struct Word {
         pub id: Uuid,
         pub base: String,
         pub word_type: WordType,
         pub recipie: Option<Vec<Vec<String>>>,
-        pub tags: HashSet<String>,
-        pub related: HashSet<(WordRelationType, Uuid)>,
+        pub tags: BTreeSet<String>,
+        pub related: BTreeSet<(WordRelationType, Uuid)>,
+        pub weight: f64,
+        pub rarity: Rarity,
+        pub rank: Option<u64>,
     }
 
-    pub fn get_wrapper_content(wrapper: &str, line: &str) -> Option<String> {
-        let regex = Regex::new(&format!(r"{}\(([a-zA-Z0-9\[\], ]+)\)", wrapper)).unwrap();
-        let capture = regex.captures(line);
-        if capture.is_some() {
-            return Some(capture.unwrap().get(1).unwrap().as_str().to_string());
-        } else {
-            return None;
-        }
+    pub fn line_tags(parsed: &ParsedLine) -> BTreeSet<String> {
+        return parsed
+            .entries
+            .iter()
+            .filter_map(|(entry, _)| match entry {
+                Entry::Tag(tag) => Some(tag.clone()),
+                _ => None,
+            })
+            .collect();
     }
 
-    pub fn get_word_tags(line: &str) -> Vec<String> {
-        let regex = Regex::new(&format!(r",?\s?{}\(([a-zA-Z0-9]+)\)", TAG_WRAPPER)).unwrap();
-        return regex
-            .find_iter(line)
-            .map(|m| {
-                regex
-                    .captures(m.as_str())
-                    .unwrap()
-                    .get(1)
-                    .unwrap()
-                    .as_str()
-                    .to_string()
+    pub fn line_weight(parsed: &ParsedLine) -> f64 {
+        return parsed
+            .entries
+            .iter()
+            .find_map(|(entry, _)| match entry {
+                Entry::Weight(weight) => Some(*weight),
+                _ => None,
             })
-            .collect();
+            .unwrap_or(1.0);
+    }
+
+    pub fn line_rarity(parsed: &ParsedLine) -> Rarity {
+        return parsed
+            .entries
+            .iter()
+            .find_map(|(entry, _)| match entry {
+                Entry::Rarity(rarity) => Some(*rarity),
+                _ => None,
+            })
+            .unwrap_or_default();
+    }
+
+    pub fn line_rank(parsed: &ParsedLine) -> Option<u64> {
+        return parsed.entries.iter().find_map(|(entry, _)| match entry {
+            Entry::Rank(rank) => Some(*rank),
+            _ => None,
+        });
     }
 
     pub fn parse_word(line: &str) -> Vec<Word> {
-        if !(line.contains(NOUN_WRAPPER) || line.contains(ADJECTIVE_WRAPPER)) {
+        return words_from_line(&parse_line(INLINE_SOURCE, 1, line));
+    }
+
+    pub fn words_from_line(parsed: &ParsedLine) -> Vec<Word> {
+        let noun_value = parsed.entries.iter().find_map(|(entry, _)| match entry {
+            Entry::Noun(value) => Some(value.clone()),
+            _ => None,
+        });
+        let adjective_value = parsed.entries.iter().find_map(|(entry, _)| match entry {
+            Entry::Adjective(value) => Some(value.clone()),
+            _ => None,
+        });
+        if noun_value.is_none() && adjective_value.is_none() {
             return vec![];
         }
         let mut output: Vec<Word> = vec![];
-        let noun_value = get_wrapper_content(NOUN_WRAPPER, line);
-        let adjective_value = get_wrapper_content(ADJECTIVE_WRAPPER, line);
-        let noun_id = Uuid::new_v4();
-        let adjective_id = Uuid::new_v4();
-        let tags: HashSet<String> = if noun_value.is_some() || adjective_value.is_some() {
-            HashSet::from_iter(get_word_tags(line).iter().cloned())
-        } else {
-            HashSet::new()
-        };
-        if noun_value.is_some() {
-            let mut rel: HashSet<(WordRelationType, Uuid)> = HashSet::new();
+        let noun_id = content_id(NOUN_WRAPPER, &parsed.key);
+        let adjective_id = content_id(ADJECTIVE_WRAPPER, &parsed.key);
+        let tags = line_tags(parsed);
+        let weight = line_weight(parsed);
+        let (rarity, rank) = (line_rarity(parsed), line_rank(parsed));
+        if let Some(noun) = noun_value {
+            let mut rel: BTreeSet<(WordRelationType, Uuid)> = BTreeSet::new();
             if adjective_value.is_some() {
                 rel.insert((WordRelationType::Adjective, adjective_id));
             }
             output.push(Word {
-                id: noun_id.clone(),
-                base: noun_value.unwrap().clone(),
+                id: noun_id,
+                base: noun,
                 word_type: WordType::Noun,
                 recipie: None,
                 tags: tags.clone(),
                 related: rel,
+                weight,
+                rarity,
+                rank,
             });
         }
 
-        if adjective_value.is_some() {
-            let mut rel: HashSet<(WordRelationType, Uuid)> = HashSet::new();
-            if output.len() > 0 {
+        if let Some(adjective) = adjective_value {
+            let mut rel: BTreeSet<(WordRelationType, Uuid)> = BTreeSet::new();
+            if !output.is_empty() {
                 rel.insert((WordRelationType::BaseNoun, noun_id));
             }
             output.push(Word {
-                id: adjective_id.clone(),
-                base: adjective_value.unwrap(),
+                id: adjective_id,
+                base: adjective,
                 word_type: WordType::Adjective,
                 recipie: None,
                 tags: tags.clone(),
                 related: rel,
+                weight,
+                rarity,
+                rank,
             });
         }
         return output;
@@ -103,7 +175,7 @@ pub mod word {
     #[test]
     fn parse_word_test() {
         let steel_words = parse_word("NOUN(steel), ADJECTIVE(steely), TAG(metal), TAG(ferrous)");
-        let steel_noun = steel_words.get(0).unwrap();
+        let steel_noun = steel_words.first().unwrap();
         let steel_adj = steel_words.get(1).unwrap();
         assert!(steel_noun.word_type.eq(&WordType::Noun));
         assert!(steel_noun.related.len().eq(&1));
@@ -112,4 +184,29 @@ pub mod word {
         assert!(steel_adj.related.len().eq(&1));
         assert!(steel_adj.tags.len().eq(&2));
     }
+
+    #[test]
+    fn test_related_words() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec![
+            "NOUN(Steel), ADJECTIVE(Steely), TAG(Metal)".to_string(),
+            "NOUN(Tin), TAG(Metal)".to_string(),
+        ]);
+        let find = |base: &str| -> Uuid {
+            return dict.words.values().find(|w| w.base.eq(base)).unwrap().id;
+        };
+        let steely = dict.related_words(&find("Steel"), WordRelationType::Adjective);
+        assert!(steely.len().eq(&1) && steely[0].base.eq("Steely"));
+        let steel = dict.related_words(&find("Steely"), WordRelationType::BaseNoun);
+        assert!(steel.len().eq(&1) && steel[0].base.eq("Steel"));
+        assert!(dict
+            .related_words(&find("Steel"), WordRelationType::BaseNoun)
+            .is_empty());
+        assert!(dict
+            .related_words(&find("Tin"), WordRelationType::Adjective)
+            .is_empty());
+        assert!(dict
+            .related_words(&Uuid::nil(), WordRelationType::Adjective)
+            .is_empty());
+    }
 }
@@ -0,0 +1,97 @@
+#[allow(clippy::module_inception)]
+pub mod error {
+    use std::{fmt, io};
+
+    use uuid::Uuid;
+
+    use crate::dictionary::{query::query::Query, word::word::WordRelationType};
+
+    #[derive(Debug)]
+    pub enum TemplaterError {
+        Io {
+            path: String,
+            source: io::Error,
+        },
+        Parse {
+            file: String,
+            line: usize,
+            message: String,
+        },
+        EmptySlot(Query),
+        UnknownTemplate(Uuid),
+        UnknownName(String),
+        // only from templates built in code, the parser keeps an unbound '@name' as text
+        UnboundVariable(String),
+        // a bound word with no form of the kind asked for by '@name.ADJECTIVE' or '@name.NOUN',
+        // only when a pick given to try_render_template_with ignores the forms it is passed
+        NoRelatedWord {
+            word: String,
+            relation: WordRelationType,
+        },
+        // a slot bound as @name and used as '@name.ADJECTIVE' or '@name.NOUN' where no word
+        // matching its query has that form
+        NoWordWithForm {
+            query: Query,
+            relation: WordRelationType,
+        },
+        // a generation session has used every word the query matches
+        Exhausted(Query),
+        // a generation session found no render of the template it had not already produced
+        ExhaustedTemplate(Uuid),
+        // TEMPLATE slots nested past the dictionary's max_template_depth
+        NestingTooDeep {
+            template: Uuid,
+            limit: usize,
+        },
+    }
+
+    impl fmt::Display for TemplaterError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                TemplaterError::Io { path, source } => {
+                    write!(f, "cannot read {}: {}", path, source)
+                }
+                TemplaterError::Parse {
+                    file,
+                    line,
+                    message,
+                } => write!(f, "{}:{}: {}", file, line, message),
+                TemplaterError::EmptySlot(query) => write!(f, "nothing matches {}", query),
+                TemplaterError::UnknownTemplate(id) => write!(f, "no template with id {}", id),
+                TemplaterError::UnknownName(name) => write!(f, "no template is named '{}'", name),
+                TemplaterError::UnboundVariable(name) => {
+                    write!(f, "@{} is used before it is bound", name)
+                }
+                TemplaterError::NoRelatedWord { word, relation } => {
+                    write!(f, "'{}' has no related {}", word, relation.keyword())
+                }
+                TemplaterError::NoWordWithForm { query, relation } => write!(
+                    f,
+                    "nothing matching {} has a related {}",
+                    query,
+                    relation.keyword()
+                ),
+                TemplaterError::Exhausted(query) => {
+                    write!(f, "every match of {} has been used", query)
+                }
+                TemplaterError::ExhaustedTemplate(id) => {
+                    write!(f, "no new render of template {}", id)
+                }
+                TemplaterError::NestingTooDeep { template, limit } => write!(
+                    f,
+                    "template {} is nested more than {} TEMPLATE slots deep",
+                    template, limit
+                ),
+            }
+        }
+    }
+
+    impl std::error::Error for TemplaterError {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                TemplaterError::Io { source, .. } => Some(source),
+                _ => None,
+            }
+        }
+    }
+}
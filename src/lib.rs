@@ -1 +1,2 @@
 pub mod dictionary;
+pub mod error;
@@ -1,34 +1,30 @@
-use std::{
-    fs::{self, File},
-    io::{self, BufRead},
-};
+use std::fs;
 
 use dictionary::dictionary::{build_dictionary, build_dictionary_from_folder, Dictionary};
 
 pub mod dictionary;
+pub mod error;
 pub mod people;
 
+#[allow(clippy::needless_return)]
 fn read_data_files() -> Vec<String> {
     let path_root = "./src/dictionary/data_files";
-    let paths = fs::read_dir(path_root).unwrap();
+    let mut paths: Vec<_> = fs::read_dir(path_root)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    paths.sort();
     let mut output: Vec<String> = Vec::new();
     for path in paths {
-        let filename = path.unwrap().file_name();
-        let data = File::open(&format!("{}/{}", path_root, filename.to_str().unwrap()))
-            .expect(&format!("Cannot open: {}", filename.into_string().unwrap()));
-        let lines = io::BufReader::new(data).lines();
-        for l in lines {
-            if l.is_ok() {
-                let line = l.unwrap();
-                if line.len() > 0 {
-                    output.push(line);
-                }
-            }
-        }
+        let data =
+            fs::read_to_string(&path).unwrap_or_else(|_| panic!("Cannot open: {}", path.display()));
+        // blank lines and comments are left for build_dictionary to skip
+        output.extend(data.lines().map(|line| line.to_string()));
     }
     return output;
 }
 
+#[allow(clippy::needless_return)]
 pub fn build_default_dictionary() -> Dictionary {
     return build_dictionary(read_data_files());
 }
@@ -1,5 +1,14 @@
+#[allow(clippy::module_inception, clippy::needless_return)]
 pub mod people {
-    use crate::dictionary::{dictionary::Dictionary, word::word::WordType};
+    use rand::Rng;
+
+    use crate::{
+        dictionary::{
+            dictionary::Dictionary, query::query::Query, session::session::GenerationSession,
+            word::word::Word,
+        },
+        error::error::TemplaterError,
+    };
 
     #[derive(PartialEq, Debug, Clone, Hash, Eq)]
     pub enum Gender {
@@ -8,58 +17,70 @@ pub mod people {
         Ambigious,
     }
 
-    pub fn build_name(dict: &Dictionary, gender: Option<Gender>) -> String {
-        let mut gender_term = "AmbiguousGender";
-        if gender.is_some() {
-            let g = gender.unwrap();
-            if g.eq(&Gender::Male) {
-                gender_term = "Male";
-            } else if g.eq(&Gender::Female) {
-                gender_term = "Female";
-            }
-        }
+    pub fn try_build_name<R: Rng + ?Sized>(
+        dict: &Dictionary,
+        gender: Option<Gender>,
+        rng: &mut R,
+    ) -> Result<String, TemplaterError> {
+        return name_from(gender, |query| dict.try_get_random_word(query, rng));
+    }
 
-        let first = dict
-            .get_random_word((
-                WordType::Noun,
-                vec![vec!["FirstName".to_string()], vec![gender_term.to_string()]],
-            ))
-            .unwrap();
-        let last = dict
-            .get_random_word((WordType::Noun, vec![vec!["LastName".to_string()]]))
-            .unwrap();
-        return format!("{} {}", first.base, last.base);
+    // draws through the session so its uniqueness and recent windows apply to the names,
+    // each name counting as one render
+    pub fn try_build_session_name<R: Rng + ?Sized>(
+        session: &mut GenerationSession,
+        gender: Option<Gender>,
+        rng: &mut R,
+    ) -> Result<String, TemplaterError> {
+        return session.as_one_render(|session| {
+            name_from(gender, |query| session.try_get_random_word(query, rng))
+        });
     }
 
-    pub fn build_description(dict: &Dictionary) -> String {
-        let hair_colour = dict
-            .get_random_word((WordType::Adjective, vec![vec!["HairColour".to_string()]]))
-            .unwrap();
-        let hair_style = dict
-            .get_random_word((
-                WordType::Adjective,
-                vec![vec!["HairStyle".to_string()], vec!["Personal".to_string()]],
-            ))
-            .unwrap();
-        let hair_state = dict
-            .get_random_word((
-                WordType::Adjective,
-                vec![vec!["HairState".to_string()], vec!["Personal".to_string()]],
-            ))
-            .unwrap();
-        let eye_colour = dict
-            .get_random_word((WordType::Adjective, vec![vec!["EyeColour".to_string()]]))
-            .unwrap();
-        let build = dict
-            .get_random_word((
-                WordType::Adjective,
-                vec![vec!["Build".to_string()], vec!["Personal".to_string()]],
-            ))
-            .unwrap();
-        return format!(
+    fn name_from<'a>(
+        gender: Option<Gender>,
+        mut pick: impl FnMut(Query) -> Result<&'a Word, TemplaterError>,
+    ) -> Result<String, TemplaterError> {
+        let gender_term = match gender {
+            Some(Gender::Male) => "Male",
+            Some(Gender::Female) => "Female",
+            _ => "AmbiguousGender",
+        };
+
+        let first = pick(Query::noun().all_of(["FirstName", gender_term]))?;
+        let last = pick(Query::noun().all_of(["LastName"]))?;
+        return Ok(format!("{} {}", first.base, last.base));
+    }
+
+    pub fn build_name<R: Rng + ?Sized>(
+        dict: &Dictionary,
+        gender: Option<Gender>,
+        rng: &mut R,
+    ) -> String {
+        return try_build_name(dict, gender, rng).unwrap();
+    }
+
+    pub fn try_build_description<R: Rng + ?Sized>(
+        dict: &Dictionary,
+        rng: &mut R,
+    ) -> Result<String, TemplaterError> {
+        let hair_colour =
+            dict.try_get_random_word(Query::adjective().all_of(["HairColour"]), rng)?;
+        let hair_style =
+            dict.try_get_random_word(Query::adjective().all_of(["HairStyle", "Personal"]), rng)?;
+        let hair_state =
+            dict.try_get_random_word(Query::adjective().all_of(["HairState", "Personal"]), rng)?;
+        let eye_colour = dict.try_get_random_word(Query::adjective().all_of(["EyeColour"]), rng)?;
+        let build =
+            dict.try_get_random_word(Query::adjective().all_of(["Build", "Personal"]), rng)?;
+        return Ok(format!(
             "They are {} with {} {} {} hair and {} eyes",
             build.base, hair_state.base, hair_style.base, hair_colour.base, eye_colour.base
-        );
+        ));
+    }
+
+    pub fn build_description<R: Rng + ?Sized>(dict: &Dictionary, rng: &mut R) -> String {
+        return try_build_description(dict, rng).unwrap();
     }
 
     #[test]
@@ -67,9 +88,67 @@ pub mod people {
         use crate::build_default_dictionary;
         let dict = build_default_dictionary();
         dict.inspect();
+        let mut rng = rand::thread_rng();
         for _i in 0..100 {
-            build_name(&dict, None);
-            build_description(&dict);
+            build_name(&dict, None, &mut rng);
+            build_description(&dict, &mut rng);
         }
     }
+
+    #[test]
+    fn test_seeded_helpers() {
+        use crate::build_default_dictionary;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_default_dictionary();
+        let generate = |seed: u64| -> Vec<String> {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20)
+                .flat_map(|_| {
+                    [
+                        build_name(&dict, Some(Gender::Female), &mut rng),
+                        build_description(&dict, &mut rng),
+                    ]
+                })
+                .collect()
+        };
+        assert!(generate(1234).eq(&generate(1234)));
+    }
+
+    #[test]
+    fn test_session_names() {
+        use crate::build_default_dictionary;
+        use crate::dictionary::session::session::RecentWindow;
+        use rand::{rngs::StdRng, SeedableRng};
+        let dict = build_default_dictionary();
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut session = GenerationSession::new(&dict);
+        session.unique_words = false;
+        session.suppress_recent(
+            Query::noun().all_of(["LastName"]),
+            RecentWindow {
+                renders: 6,
+                weight: 0.0,
+            },
+        );
+        let surnames: Vec<String> = (0..30)
+            .map(|_| {
+                let name = try_build_session_name(&mut session, None, &mut rng).unwrap();
+                name.split(' ').next_back().unwrap().to_string()
+            })
+            .collect();
+        // each name is one render, so a surname is kept out of the next six names
+        assert!(surnames.windows(7).all(|w| !w[1..].contains(&w[0])));
+    }
+
+    #[test]
+    fn test_try_helpers() {
+        use crate::dictionary::dictionary::build_dictionary;
+        let dict = build_dictionary(vec!["NOUN(Smith), TAG(LastName)".to_string()]);
+        let mut rng = rand::thread_rng();
+        assert!(matches!(
+            try_build_name(&dict, Some(Gender::Male), &mut rng),
+            Err(TemplaterError::EmptySlot(_))
+        ));
+        assert!(try_build_description(&dict, &mut rng).is_err());
+    }
 }